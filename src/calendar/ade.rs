@@ -0,0 +1,194 @@
+use anyhow::{bail, Context};
+use bytes::Bytes;
+use chrono::Utc;
+use log::info;
+use reqwest::{Client, StatusCode};
+use roxmltree::Document;
+
+use crate::cfg::{AdeConfig, Auth, CalendarItem};
+
+use super::source::SourceState;
+
+/// Path of the ADE web api, used to open a session
+const WEB_API: &str = "jsp/webapi";
+/// Path of the iCalendar export of ADE
+const EXPORT: &str = "jsp/custom/modules/plannings/anonymous_cal.jsp";
+/// Format of the dates of the export request
+const DATE_FORMAT: &str = "%Y-%m-%d";
+
+/// Returns the url of an endpoint of an ADE server
+fn endpoint(base: &str, path: &str) -> String {
+    format!("{}/{path}", base.trim_end_matches('/'))
+}
+
+/// Reads the id of the session from the answer of the `connect` function (`<session id="..."/>`)
+fn parse_session(body: &str) -> Result<String, anyhow::Error> {
+    let document = Document::parse(body).context("invalid answer to the ADE connection")?;
+    let root = document.root_element();
+
+    if root.has_tag_name("error") {
+        bail!(
+            "ADE refused the connection: {}",
+            root.attribute("name").unwrap_or("unknown error")
+        );
+    }
+    if !root.has_tag_name("session") {
+        bail!(
+            "unexpected answer to the ADE connection: {}",
+            root.tag_name().name()
+        );
+    }
+
+    root.attribute("id")
+        .map(ToString::to_string)
+        .context("the ADE session has no id")
+}
+
+/// Opens a session on the ADE api and selects the project of the calendar.
+/// The urls of these requests hold the credentials, so they are removed from the errors.
+async fn connect(
+    client: &Client,
+    base: &str,
+    settings: &AdeConfig,
+    auth: &Auth,
+) -> Result<String, anyhow::Error> {
+    let Auth::Basic { username, password } = auth else {
+        bail!("ADE sources only support the basic authentication");
+    };
+    let url = endpoint(base, WEB_API);
+
+    let body = client
+        .get(&url)
+        .query(&[
+            ("function", "connect"),
+            ("login", username),
+            ("password", &password.expose()?),
+        ])
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(reqwest::Error::without_url)
+        .with_context(|| format!("failed to connect to {url}"))?
+        .text()
+        .await
+        .map_err(reqwest::Error::without_url)?;
+    let session = parse_session(&body)?;
+
+    client
+        .get(&url)
+        .query(&[
+            ("sessionId", session.as_str()),
+            ("function", "setProject"),
+            ("projectId", &settings.project.to_string()),
+        ])
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(reqwest::Error::without_url)
+        .with_context(|| format!("failed to select the project {}", settings.project))?;
+
+    info!("opened an ADE session on {base}");
+    Ok(session)
+}
+
+/// Exports the events of the resources of the calendar, from today to the end of the range
+/// covered by the calendar. Returns `None` when ADE refused the session.
+async fn export(
+    client: &Client,
+    base: &str,
+    settings: &AdeConfig,
+    session: Option<&str>,
+    watch_item: &CalendarItem,
+) -> Result<Option<Bytes>, anyhow::Error> {
+    let from = Utc::now();
+    let to = from + watch_item.time_range()?;
+
+    let resources = settings
+        .resources
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(",");
+    let mut query = vec![
+        ("resources", resources),
+        ("projectId", settings.project.to_string()),
+        ("calType", "ical".to_string()),
+        ("firstDate", from.format(DATE_FORMAT).to_string()),
+        ("lastDate", to.format(DATE_FORMAT).to_string()),
+    ];
+    if let Some(session) = session {
+        query.push(("sessionId", session.to_string()));
+    }
+
+    let url = endpoint(base, EXPORT);
+    let response = client
+        .get(&url)
+        .query(&query)
+        .send()
+        .await
+        .map_err(reqwest::Error::without_url)
+        .with_context(|| format!("failed to export the calendar from {url}"))?;
+
+    if session.is_some()
+        && matches!(
+            response.status(),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+        )
+    {
+        return Ok(None);
+    }
+
+    let body = response
+        .error_for_status()
+        .map_err(reqwest::Error::without_url)
+        .with_context(|| format!("failed to export the calendar from {url}"))?
+        .bytes()
+        .await
+        .map_err(reqwest::Error::without_url)?;
+
+    // ADE answers with its login page or an error page when the session expired
+    if !body.trim_ascii_start().starts_with(b"BEGIN:VCALENDAR") {
+        if session.is_some() {
+            return Ok(None);
+        }
+        bail!("ADE didn't answer with a calendar");
+    }
+
+    Ok(Some(body))
+}
+
+/// Fetches the events of an ADE calendar.
+/// Calendars without authentication use the anonymous export. The others open a session
+/// with their credentials, which is kept until ADE refuses it and a new one is opened.
+pub async fn fetch(
+    client: &Client,
+    base: &str,
+    watch_item: &CalendarItem,
+    state: &mut SourceState,
+) -> Result<Bytes, anyhow::Error> {
+    let settings = watch_item
+        .ade
+        .as_ref()
+        .context("missing the ade settings of the calendar")?;
+
+    let Some(auth) = &watch_item.auth else {
+        return export(client, base, settings, None, watch_item)
+            .await?
+            .context("ADE refused the anonymous export");
+    };
+
+    if let Some(session) = &state.session {
+        if let Some(body) = export(client, base, settings, Some(session), watch_item).await? {
+            return Ok(body);
+        }
+        info!("the ADE session on {base} expired");
+    }
+
+    let session = connect(client, base, settings, auth).await?;
+    let body = export(client, base, settings, Some(&session), watch_item)
+        .await?
+        .context("ADE refused the export with a new session")?;
+    state.session = Some(session);
+
+    Ok(body)
+}
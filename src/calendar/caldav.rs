@@ -0,0 +1,608 @@
+use anyhow::{bail, Context};
+use bytes::Bytes;
+use chrono::{Duration, Utc};
+use log::{debug, info, warn};
+use reqwest::{header::CONTENT_TYPE, Client, Method};
+use roxmltree::{Document, Node};
+
+use crate::cfg::CalendarItem;
+
+use super::source::{authenticate, SourceState};
+
+/// Namespace of the `DAV:` elements (RFC 4918)
+const DAV: &str = "DAV:";
+/// Namespace of the calendar elements (RFC 4791)
+const CALDAV: &str = "urn:ietf:params:xml:ns:caldav";
+/// Format of the bounds of a `time-range` filter
+const TIME_RANGE_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+/// Maximum age of the time-range query of a collection.
+/// The range moves with the time and the objects entering it aren't changes of the collection,
+/// so they are only read by a new query.
+const QUERY_REFRESH: Duration = Duration::hours(1);
+
+/// A resource listed in a multistatus response
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Resource {
+    href: String,
+    /// Content of the calendar object (`calendar-data`)
+    data: Option<String>,
+    /// The resource was deleted (`404` status in a `sync-collection` report)
+    removed: bool,
+}
+
+/// A parsed multistatus response (RFC 4918, section 13)
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Multistatus {
+    resources: Vec<Resource>,
+    sync_token: Option<String>,
+}
+
+/// Returns the first child of a node with the given name
+fn child<'a, 'input>(
+    node: Node<'a, 'input>,
+    namespace: &str,
+    name: &str,
+) -> Option<Node<'a, 'input>> {
+    node.children()
+        .find(|child| child.has_tag_name((namespace, name)))
+}
+
+/// Checks if a `status` element holds a successful status (`HTTP/1.1 200 OK`)
+fn is_success(status: Option<Node>) -> bool {
+    status
+        .and_then(|status| status.text())
+        .and_then(|status| status.split_whitespace().nth(1))
+        .is_some_and(|code| code.starts_with('2'))
+}
+
+/// Parses the multistatus response of a PROPFIND or REPORT request
+fn parse_multistatus(body: &str) -> Result<Multistatus, anyhow::Error> {
+    let document = Document::parse(body).context("invalid xml in the multistatus response")?;
+    let root = document.root_element();
+    if !root.has_tag_name((DAV, "multistatus")) {
+        bail!(
+            "expected a multistatus response, got {}",
+            root.tag_name().name()
+        );
+    }
+
+    let mut multistatus = Multistatus {
+        // the token is a child of the root for a sync-collection report and a property for a PROPFIND
+        sync_token: root
+            .descendants()
+            .find(|node| node.has_tag_name((DAV, "sync-token")))
+            .and_then(|node| node.text())
+            .map(|token| token.trim().to_string()),
+        ..Default::default()
+    };
+
+    for response in root
+        .children()
+        .filter(|node| node.has_tag_name((DAV, "response")))
+    {
+        let href = child(response, DAV, "href")
+            .and_then(|href| href.text())
+            .context("response without href")?
+            .trim()
+            .to_string();
+
+        // a status outside of a propstat describes the whole resource
+        if let Some(status) = child(response, DAV, "status") {
+            multistatus.resources.push(Resource {
+                href,
+                data: None,
+                removed: !is_success(Some(status)),
+            });
+            continue;
+        }
+
+        let data = response
+            .children()
+            .filter(|node| node.has_tag_name((DAV, "propstat")))
+            .filter(|propstat| is_success(child(*propstat, DAV, "status")))
+            .filter_map(|propstat| child(propstat, DAV, "prop"))
+            .find_map(|prop| child(prop, CALDAV, "calendar-data"))
+            .and_then(|data| data.text())
+            .map(ToString::to_string);
+
+        multistatus.resources.push(Resource {
+            href,
+            data,
+            removed: false,
+        });
+    }
+
+    Ok(multistatus)
+}
+
+/// Escapes a text inserted in a xml request
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Sends a `PROPFIND` or `REPORT` request with the authentication of the calendar
+async fn request(
+    client: &Client,
+    url: &str,
+    watch_item: &CalendarItem,
+    method: &str,
+    depth: &str,
+    body: String,
+) -> Result<reqwest::Response, anyhow::Error> {
+    let request = client
+        .request(Method::from_bytes(method.as_bytes())?, url)
+        .header("Depth", depth)
+        .header(CONTENT_TYPE, "application/xml; charset=utf-8")
+        .body(body);
+
+    Ok(authenticate(request, watch_item)?.send().await?)
+}
+
+/// Reads the calendar objects of the collection in the range covered by the calendar
+/// and the current sync token of the collection.
+async fn query(
+    client: &Client,
+    url: &str,
+    watch_item: &CalendarItem,
+    state: &mut SourceState,
+) -> Result<(), anyhow::Error> {
+    // the token is read first so the changes made during the query are in the next sync
+    let response = request(
+        client,
+        url,
+        watch_item,
+        "PROPFIND",
+        "0",
+        format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<D:propfind xmlns:D="{DAV}">
+  <D:prop><D:sync-token/></D:prop>
+</D:propfind>"#
+        ),
+    )
+    .await?
+    .error_for_status()?;
+    let sync_token = parse_multistatus(&response.text().await?)?.sync_token;
+
+    let from = Utc::now();
+    let to = from + watch_item.time_range()?;
+    let response = request(
+        client,
+        url,
+        watch_item,
+        "REPORT",
+        "1",
+        format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<C:calendar-query xmlns:D="{DAV}" xmlns:C="{CALDAV}">
+  <D:prop><D:getetag/><C:calendar-data/></D:prop>
+  <C:filter>
+    <C:comp-filter name="VCALENDAR">
+      <C:comp-filter name="VEVENT">
+        <C:time-range start="{}" end="{}"/>
+      </C:comp-filter>
+    </C:comp-filter>
+  </C:filter>
+</C:calendar-query>"#,
+            from.format(TIME_RANGE_FORMAT),
+            to.format(TIME_RANGE_FORMAT),
+        ),
+    )
+    .await?
+    .error_for_status()?;
+    let multistatus = parse_multistatus(&response.text().await?)?;
+
+    state.objects = multistatus
+        .resources
+        .into_iter()
+        .filter_map(|resource| Some((resource.href, resource.data?)))
+        .collect();
+    state.sync_token = sync_token;
+    state.queried = Some(from);
+
+    info!(
+        "queried {} calendar objects from {}",
+        state.objects.len(),
+        url
+    );
+    Ok(())
+}
+
+/// Applies the changes made to the collection since the last sync (`sync-collection` report).
+/// Returns the number of changed resources, or `None` when the token was refused by the server
+/// or the server didn't send the content of the objects; a full query is then needed.
+async fn sync(
+    client: &Client,
+    url: &str,
+    watch_item: &CalendarItem,
+    token: &str,
+    state: &mut SourceState,
+) -> Result<Option<usize>, anyhow::Error> {
+    let response = request(
+        client,
+        url,
+        watch_item,
+        "REPORT",
+        "0",
+        format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<D:sync-collection xmlns:D="{DAV}" xmlns:C="{CALDAV}">
+  <D:sync-token>{}</D:sync-token>
+  <D:sync-level>1</D:sync-level>
+  <D:prop><D:getetag/><C:calendar-data/></D:prop>
+</D:sync-collection>"#,
+            escape(token)
+        ),
+    )
+    .await?;
+
+    // the token expired or the server doesn't support the report
+    if !response.status().is_success() {
+        warn!(
+            "the sync of {} failed with the status {}",
+            url,
+            response.status()
+        );
+        return Ok(None);
+    }
+
+    let multistatus = parse_multistatus(&response.text().await?)?;
+    if multistatus
+        .resources
+        .iter()
+        .any(|resource| !resource.removed && resource.data.is_none())
+    {
+        debug!("{url} didn't send the content of the changed objects");
+        return Ok(None);
+    }
+
+    let changes = multistatus.resources.len();
+    for resource in multistatus.resources {
+        match resource.data {
+            Some(data) => state.objects.insert(resource.href, data),
+            None => state.objects.remove(&resource.href),
+        };
+    }
+    state.sync_token = multistatus.sync_token;
+
+    Ok(Some(changes))
+}
+
+/// Fetches a `caldav://` collection.
+/// The objects are read with a `calendar-query` over the range covered by the calendar, then
+/// only the changes are fetched using the sync token of the collection (RFC 6578).
+/// Since the range moves with the time, a full query is made when the last one is older than
+/// [`QUERY_REFRESH`] or when `refresh` is set.
+/// Returns `None` when nothing changed since the last sync.
+pub async fn fetch(
+    client: &Client,
+    url: &str,
+    watch_item: &CalendarItem,
+    state: &mut SourceState,
+    refresh: bool,
+) -> Result<Option<Bytes>, anyhow::Error> {
+    let refresh = refresh
+        || state
+            .queried
+            .is_none_or(|queried| Utc::now() - queried > QUERY_REFRESH);
+
+    let synced = match state.sync_token.clone() {
+        Some(token) if !refresh => sync(client, url, watch_item, &token, state).await?,
+        _ => None,
+    };
+
+    match synced {
+        Some(0) => return Ok(None),
+        Some(changes) => debug!("synced {changes} changes from {url}"),
+        None => query(client, url, watch_item, state).await?,
+    }
+
+    // each object is a VCALENDAR block
+    let mut body = String::new();
+    for data in state.objects.values() {
+        body.push_str(data);
+        body.push('\n');
+    }
+
+    Ok(Some(body.into()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fmt::Write,
+        sync::{Arc, Mutex},
+    };
+
+    use chrono::{Duration, Utc};
+    use reqwest::Client;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    use crate::{calendar::source::SourceState, cfg::CalendarItem};
+
+    use super::{fetch, parse_multistatus, Resource};
+
+    /// Requests received by the stub server: the method and the body
+    type Requests = Arc<Mutex<Vec<(String, String)>>>;
+
+    /// Starts a server answering each request with the next response (status and body).
+    /// Returns the url of the collection and the requests received by the server.
+    async fn server(responses: Vec<(u16, String)>) -> (String, Requests) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/user/calendar/", listener.local_addr().unwrap());
+        let requests = Requests::default();
+
+        let received = requests.clone();
+        tokio::spawn(async move {
+            for (status, body) in responses {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = BufReader::new(stream);
+
+                let mut line = String::new();
+                stream.read_line(&mut line).await.unwrap();
+                let method = line.split_whitespace().next().unwrap().to_string();
+                let mut length = 0;
+                loop {
+                    line.clear();
+                    stream.read_line(&mut line).await.unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut request = vec![0; length];
+                stream.read_exact(&mut request).await.unwrap();
+                received
+                    .lock()
+                    .unwrap()
+                    .push((method, String::from_utf8(request).unwrap()));
+
+                let response = format!(
+                    "HTTP/1.1 {status} Stub\r\nContent-Type: application/xml\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+                stream.shutdown().await.unwrap();
+            }
+        });
+
+        (url, requests)
+    }
+
+    /// A multistatus response listing the given objects (href and content, `None` if deleted)
+    fn multistatus(objects: &[(&str, Option<&str>)], token: &str) -> String {
+        let mut body = r#"<?xml version="1.0" encoding="utf-8"?>
+<D:multistatus xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">"#
+            .to_string();
+        for (href, data) in objects {
+            match data {
+                Some(data) => write!(
+                    body,
+                    "<D:response><D:href>{href}</D:href><D:propstat>\
+                     <D:prop><C:calendar-data>{data}</C:calendar-data></D:prop>\
+                     <D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>"
+                ),
+                None => write!(
+                    body,
+                    "<D:response><D:href>{href}</D:href>\
+                     <D:status>HTTP/1.1 404 Not Found</D:status></D:response>"
+                ),
+            }
+            .unwrap();
+        }
+        body + &format!("<D:sync-token>{token}</D:sync-token></D:multistatus>")
+    }
+
+    /// The token of the collection, answered to a PROPFIND
+    fn token(token: &str) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<D:multistatus xmlns:D="DAV:"><D:response><D:href>/user/calendar/</D:href><D:propstat>
+<D:prop><D:sync-token>{token}</D:sync-token></D:prop><D:status>HTTP/1.1 200 OK</D:status>
+</D:propstat></D:response></D:multistatus>"#
+        )
+    }
+
+    fn item() -> CalendarItem {
+        CalendarItem {
+            time_amount: "2w".to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the method of each request and the report it holds
+    fn kinds(requests: &Requests) -> Vec<(String, &'static str)> {
+        requests
+            .lock()
+            .unwrap()
+            .drain(..)
+            .map(|(method, body)| {
+                let kind = ["propfind", "calendar-query", "sync-collection"]
+                    .into_iter()
+                    .find(|kind| body.contains(kind))
+                    .unwrap_or_default();
+                (method, kind)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn sync_flow() {
+        let (url, requests) = server(vec![
+            (207, token("t1")),
+            (
+                207,
+                multistatus(&[("1.ics", Some("Maths")), ("2.ics", Some("Physique"))], ""),
+            ),
+            (
+                207,
+                multistatus(&[("1.ics", Some("Chimie")), ("2.ics", None)], "t2"),
+            ),
+            // the token is refused
+            (403, String::new()),
+            (207, token("t3")),
+            (207, multistatus(&[("3.ics", Some("Anglais"))], "")),
+        ])
+        .await;
+        let client = Client::new();
+        let mut state = SourceState::default();
+
+        // the objects are read with a query, after the token
+        let body = fetch(&client, &url, &item(), &mut state, false)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(body, "Maths\nPhysique\n");
+        assert_eq!(state.sync_token.as_deref(), Some("t1"));
+        assert_eq!(
+            kinds(&requests),
+            [
+                ("PROPFIND".to_string(), "propfind"),
+                ("REPORT".to_string(), "calendar-query")
+            ]
+        );
+
+        // then only the changes are synced
+        let body = fetch(&client, &url, &item(), &mut state, false)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(body, "Chimie\n");
+        assert_eq!(state.sync_token.as_deref(), Some("t2"));
+        assert!(requests.lock().unwrap()[0].1.contains("<D:sync-token>t1<"));
+        assert_eq!(
+            kinds(&requests),
+            [("REPORT".to_string(), "sync-collection")]
+        );
+
+        // a refused token is replaced by a new query
+        let body = fetch(&client, &url, &item(), &mut state, false)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(body, "Anglais\n");
+        assert_eq!(state.sync_token.as_deref(), Some("t3"));
+        assert_eq!(
+            kinds(&requests),
+            [
+                ("REPORT".to_string(), "sync-collection"),
+                ("PROPFIND".to_string(), "propfind"),
+                ("REPORT".to_string(), "calendar-query")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn query_refresh() {
+        let (url, requests) = server(vec![
+            (207, token("t2")),
+            (207, multistatus(&[("1.ics", Some("Chimie"))], "")),
+        ])
+        .await;
+        let mut state = SourceState {
+            sync_token: Some("t1".to_string()),
+            objects: [("1.ics".to_string(), "Maths".to_string())].into(),
+            queried: Some(Utc::now() - Duration::hours(2)),
+            ..Default::default()
+        };
+
+        // the range moved since the last query: the objects are queried again
+        let body = fetch(&Client::new(), &url, &item(), &mut state, false)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(body, "Chimie\n");
+        assert_eq!(state.sync_token.as_deref(), Some("t2"));
+        assert!(state.queried.unwrap() > Utc::now() - Duration::minutes(1));
+        assert_eq!(
+            kinds(&requests),
+            [
+                ("PROPFIND".to_string(), "propfind"),
+                ("REPORT".to_string(), "calendar-query")
+            ]
+        );
+    }
+
+    #[test]
+    fn calendar_query() {
+        let multistatus = parse_multistatus(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<multistatus xmlns="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">
+  <response>
+    <href>/user/calendar/1.ics</href>
+    <propstat>
+      <prop>
+        <getetag>"1"</getetag>
+        <C:calendar-data>BEGIN:VCALENDAR
+END:VCALENDAR</C:calendar-data>
+      </prop>
+      <status>HTTP/1.1 200 OK</status>
+    </propstat>
+  </response>
+</multistatus>"#,
+        )
+        .unwrap();
+
+        assert_eq!(multistatus.sync_token, None);
+        assert_eq!(
+            multistatus.resources,
+            vec![Resource {
+                href: "/user/calendar/1.ics".to_string(),
+                data: Some("BEGIN:VCALENDAR\nEND:VCALENDAR".to_string()),
+                removed: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn sync_collection() {
+        let multistatus = parse_multistatus(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<D:multistatus xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">
+  <D:response>
+    <D:href>/user/calendar/1.ics</D:href>
+    <D:propstat>
+      <D:prop><D:getetag>"2"</D:getetag><C:calendar-data>data</C:calendar-data></D:prop>
+      <D:status>HTTP/1.1 200 OK</D:status>
+    </D:propstat>
+  </D:response>
+  <D:response>
+    <D:href>/user/calendar/2.ics</D:href>
+    <D:status>HTTP/1.1 404 Not Found</D:status>
+  </D:response>
+  <D:sync-token>http://radicale.org/ns/sync/abc</D:sync-token>
+</D:multistatus>"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            multistatus.sync_token.as_deref(),
+            Some("http://radicale.org/ns/sync/abc")
+        );
+        assert_eq!(
+            multistatus.resources,
+            vec![
+                Resource {
+                    href: "/user/calendar/1.ics".to_string(),
+                    data: Some("data".to_string()),
+                    removed: false,
+                },
+                Resource {
+                    href: "/user/calendar/2.ics".to_string(),
+                    data: None,
+                    removed: true,
+                }
+            ]
+        );
+    }
+}
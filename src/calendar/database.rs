@@ -0,0 +1,427 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::{bail, Context};
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+
+use crate::cfg::CalendarItem;
+
+use super::{
+    history::History,
+    parser::clean_stored_text,
+    schedule::{Data, PendingData, Sources},
+    Event,
+};
+
+/// Magic number at the start of the database file
+const MAGIC: &[u8; 4] = b"TMDB";
+/// Version of the layout written by this version of the bot.
+/// It must be increased with a new migration each time a persisted type changes.
+pub const VERSION: u16 = 1;
+
+/// Content of the database, written by `encode`
+#[derive(Debug, Default, Deserialize)]
+pub struct Database {
+    pub data: Data,
+    pub sources: Sources,
+    pub history: History,
+    pub pending: PendingData,
+}
+
+/// Writes the header and the content of the database in the current layout
+pub fn encode(
+    data: &Data,
+    sources: &Sources,
+    history: &History,
+    pending: &PendingData,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend(VERSION.to_le_bytes());
+
+    Ok(postcard::to_extend(
+        &(data, sources, history, pending),
+        bytes,
+    )?)
+}
+
+/// Layouts of the previous versions of the database
+mod legacy {
+    use std::collections::HashMap;
+
+    use chrono::{DateTime, Utc};
+    use serde::Deserialize;
+
+    /// An event in the first layout, before the all-day events and the statuses
+    #[derive(Deserialize)]
+    pub struct EventV0 {
+        pub summary: String,
+        pub start: DateTime<Utc>,
+        pub end: DateTime<Utc>,
+        pub location: String,
+        pub description: String,
+        pub uid: String,
+    }
+
+    /// The first layout, without header: the events of each calendar
+    pub type DatabaseV0 = HashMap<String, Vec<EventV0>>;
+}
+
+/// The database in one of the supported layouts
+enum Layout {
+    V0(legacy::DatabaseV0),
+    V1(Database),
+}
+
+impl Layout {
+    fn read(version: u16, payload: &[u8]) -> Result<Self, postcard::Error> {
+        Ok(match version {
+            0 => Self::V0(postcard::from_bytes(payload)?),
+            _ => Self::V1(postcard::from_bytes(payload)?),
+        })
+    }
+
+    /// Converts the database to the next layout.
+    /// `calendars` are the calendars of the configuration, used to clean the text of the events.
+    fn upgrade(self, calendars: &HashMap<String, CalendarItem>) -> Self {
+        match self {
+            Self::V0(events) => Self::V1(Database {
+                data: events
+                    .into_iter()
+                    .map(|(name, events)| {
+                        let rules = calendars.get(&name).map_or_else(
+                            || CalendarItem::default().cleanup,
+                            |item| item.cleanup.clone(),
+                        );
+                        let calendar = events
+                            .into_iter()
+                            .map(|event| {
+                                let mut event = Event {
+                                    summary: event.summary,
+                                    start: event.start,
+                                    end: event.end,
+                                    location: event.location,
+                                    description: event.description,
+                                    uid: event.uid,
+                                    ..Default::default()
+                                };
+                                // the text was stored escaped, it would be seen as updated
+                                // by the next fetch
+                                clean_stored_text(&mut event, &rules);
+                                Arc::new(event)
+                            })
+                            .collect();
+                        (name, calendar)
+                    })
+                    .collect(),
+                sources: Sources::default(),
+                history: History::default(),
+                pending: PendingData::default(),
+            }),
+            Self::V1(database) => Self::V1(database),
+        }
+    }
+}
+
+/// Reads the version of the layout of a database file and returns it with the content.
+/// A file without header has the first layout.
+fn split_header(bytes: &[u8]) -> Result<(u16, &[u8]), anyhow::Error> {
+    match bytes.strip_prefix(MAGIC) {
+        Some(rest) => {
+            let (version, payload) = rest
+                .split_first_chunk::<2>()
+                .context("the header of the database is truncated")?;
+            Ok((u16::from_le_bytes(*version), payload))
+        }
+        None => Ok((0, bytes)),
+    }
+}
+
+/// Reads a database file, upgrading it to the current layout.
+/// Returns the version of the file with the database.
+pub fn decode(
+    bytes: &[u8],
+    calendars: &HashMap<String, CalendarItem>,
+) -> Result<(u16, Database), anyhow::Error> {
+    let (version, payload) = split_header(bytes)?;
+
+    if version > VERSION {
+        bail!(
+            "the database was written by a newer version of the bot (layout v{version}, \
+             this version supports up to v{VERSION}), update the bot or restore a backup"
+        );
+    }
+
+    let mut layout = Layout::read(version, payload)
+        .with_context(|| format!("failed to read the database (layout v{version})"))?;
+    let mut current = version;
+    while current < VERSION {
+        layout = layout.upgrade(calendars);
+        current += 1;
+        info!("migrated the database to the layout v{current}");
+    }
+
+    match layout {
+        Layout::V1(database) => Ok((version, database)),
+        Layout::V0(_) => {
+            unreachable!("the database is migrated to the current layout")
+        }
+    }
+}
+
+/// Path of the n-th previous version of the database
+fn snapshot_path(path: &str, index: usize) -> String {
+    format!("{path}.{index}")
+}
+
+/// Writes the database without ever leaving a partial file: the content is written to a
+/// temporary file which replaces the database once it's on the disk.
+/// The replaced file becomes the first of the `snapshots` previous versions kept next to it.
+pub fn write(path: &str, bytes: &[u8], snapshots: usize) -> Result<(), anyhow::Error> {
+    let temporary = format!("{path}.tmp");
+    let mut file = File::create(&temporary)
+        .with_context(|| format!("failed to create the temporary database {temporary}"))?;
+    file.write_all(bytes)
+        .and_then(|()| file.sync_all())
+        .with_context(|| format!("failed to write the temporary database {temporary}"))?;
+    drop(file);
+
+    if snapshots > 0 && Path::new(path).exists() {
+        // the oldest snapshot is overwritten
+        for index in (1..snapshots).rev() {
+            let snapshot = snapshot_path(path, index);
+            if Path::new(&snapshot).exists() {
+                fs::rename(&snapshot, snapshot_path(path, index + 1))?;
+            }
+        }
+
+        let snapshot = snapshot_path(path, 1);
+        match fs::remove_file(&snapshot) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+        // a link is enough since the database is replaced by another file
+        if fs::hard_link(path, &snapshot).is_err() {
+            fs::copy(path, &snapshot)
+                .with_context(|| format!("failed to save the snapshot {snapshot}"))?;
+        }
+    }
+
+    fs::rename(&temporary, path)
+        .with_context(|| format!("failed to replace the database {path}"))?;
+
+    // the rename is only durable once the directory is on the disk (not supported everywhere)
+    if let Some(directory) = Path::new(path).parent() {
+        let directory = if directory.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            directory
+        };
+        if let Err(err) = File::open(directory).and_then(|directory| directory.sync_all()) {
+            debug!("failed to sync the directory of the database: {err}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads the database file, or its latest valid snapshot when it can't be decoded.
+/// The broken file is kept aside and replaced by the snapshot.
+/// Returns `None` when there is no database yet.
+pub fn load(
+    path: &str,
+    snapshots: usize,
+    calendars: &HashMap<String, CalendarItem>,
+) -> Result<Option<(u16, Database)>, anyhow::Error> {
+    let error = match fs::read(path) {
+        Ok(bytes) => {
+            // the snapshots are older, they can't replace a file of a newer version
+            if split_header(&bytes).is_ok_and(|(version, _)| version > VERSION) {
+                return decode(&bytes, calendars)
+                    .map(Some)
+                    .with_context(|| format!("failed to load the database {path}"));
+            }
+            match decode(&bytes, calendars) {
+                Ok(database) => return Ok(Some(database)),
+                Err(err) => err,
+            }
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => err.into(),
+    }
+    .context(format!("failed to load the database {path}"));
+    error!("{error:#}");
+
+    for index in 1..=snapshots {
+        let snapshot = snapshot_path(path, index);
+        let bytes = match fs::read(&snapshot) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => break,
+            Err(err) => {
+                warn!("failed to read the snapshot {snapshot}: {err}");
+                continue;
+            }
+        };
+
+        match decode(&bytes, calendars) {
+            Ok(database) => {
+                let broken = format!("{path}.broken");
+                fs::rename(path, &broken)
+                    .with_context(|| format!("failed to move the broken database to {broken}"))?;
+                write(path, &bytes, 0)?;
+
+                warn!("restored the database from the snapshot {snapshot}, the broken file is kept in {broken}");
+                return Ok(Some(database));
+            }
+            Err(err) => warn!("the snapshot {snapshot} can't be used: {err:#}"),
+        }
+    }
+
+    Err(error)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, fs, path::Path, sync::Arc};
+
+    use chrono::{DateTime, Utc};
+    use serde::Serialize;
+    use tempfile::TempDir;
+
+    use crate::calendar::{
+        history::History,
+        schedule::{Data, PendingData},
+        settle, Event, Status, UpdateResult,
+    };
+
+    use super::{decode, encode, load, write, MAGIC, VERSION};
+
+    /// An event in the first layout
+    #[derive(Serialize)]
+    struct EventV0 {
+        summary: String,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        location: String,
+        description: String,
+        uid: String,
+    }
+
+    #[test]
+    fn migrate_legacy() {
+        let start = DateTime::from_timestamp(3600, 0).unwrap();
+        let legacy = HashMap::from([(
+            "test".to_string(),
+            vec![EventV0 {
+                summary: r"Maths\, TD".to_string(),
+                start,
+                end: start,
+                location: r"A001\; A002 ".to_string(),
+                description: r"\nGroupe A\n\n".to_string(),
+                uid: "1".to_string(),
+            }],
+        )]);
+
+        let (version, database) =
+            decode(&postcard::to_allocvec(&legacy).unwrap(), &HashMap::new()).unwrap();
+        assert_eq!(version, 0);
+
+        let event = &database.data["test"].get("1").unwrap();
+        // the text is decoded and cleaned like the fetched events
+        assert_eq!(event.summary, "Maths, TD");
+        assert_eq!(event.location, "A001; A002");
+        assert_eq!(event.description, "Groupe A");
+        assert_eq!(event.status, Status::Confirmed);
+        assert!(!event.all_day);
+    }
+
+    #[test]
+    fn current_layout() {
+        let event = Event {
+            uid: "1".to_string(),
+            ..Default::default()
+        };
+        let data = Data::from([(
+            "test".to_string(),
+            std::iter::once(Arc::new(event)).collect(),
+        )]);
+
+        let mut pending = PendingData::new();
+        settle::record(
+            pending.entry("test".to_string()).or_default(),
+            None,
+            vec![UpdateResult::Created(Arc::new(Event {
+                uid: "2".to_string(),
+                ..Default::default()
+            }))],
+            DateTime::UNIX_EPOCH,
+        );
+
+        let bytes = encode(&data, &HashMap::new(), &History::default(), &pending).unwrap();
+        assert!(bytes.starts_with(MAGIC));
+
+        let (version, database) = decode(&bytes, &HashMap::new()).unwrap();
+        assert_eq!(version, VERSION);
+        assert!(database.data["test"].get("1").is_some());
+        assert_eq!(database.pending, pending);
+    }
+
+    #[test]
+    fn newer_layout() {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend((VERSION + 1).to_le_bytes());
+
+        let err = decode(&bytes, &HashMap::new()).unwrap_err();
+        assert!(err.to_string().contains("newer version"));
+    }
+
+    #[test]
+    fn rotate_snapshots() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("db").display().to_string();
+
+        for content in ["1", "2", "3", "4"] {
+            write(&path, content.as_bytes(), 2).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "4");
+        assert_eq!(fs::read_to_string(format!("{path}.1")).unwrap(), "3");
+        assert_eq!(fs::read_to_string(format!("{path}.2")).unwrap(), "2");
+        assert!(!Path::new(&format!("{path}.3")).exists());
+        assert!(!Path::new(&format!("{path}.tmp")).exists());
+    }
+
+    #[test]
+    fn snapshot_fallback() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("db").display().to_string();
+        let empty = encode(
+            &Data::new(),
+            &HashMap::new(),
+            &History::default(),
+            &PendingData::new(),
+        )
+        .unwrap();
+
+        assert!(load(&path, 2, &HashMap::new()).unwrap().is_none());
+
+        write(&path, &empty, 2).unwrap();
+        // the write was cut
+        write(&path, &empty[..=MAGIC.len()], 2).unwrap();
+
+        let (version, _) = load(&path, 2, &HashMap::new()).unwrap().unwrap();
+        assert_eq!(version, VERSION);
+        assert_eq!(fs::read(&path).unwrap(), empty);
+        assert!(Path::new(&format!("{path}.broken")).exists());
+
+        // without a valid snapshot, the error is reported
+        fs::write(&path, b"broken").unwrap();
+        fs::remove_file(format!("{path}.1")).unwrap();
+        assert!(load(&path, 0, &HashMap::new()).is_err());
+    }
+}
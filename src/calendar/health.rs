@@ -0,0 +1,191 @@
+use chrono::{DateTime, Utc};
+use poise::serenity_prelude::{Color, CreateEmbed, CreateEmbedFooter};
+
+/// Health of the source of a calendar, updated after each fetch
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Health {
+    /// Last successful fetch of the source
+    pub last_success: Option<DateTime<Utc>>,
+    /// Last fetch of the source, successful or not
+    pub last_attempt: Option<DateTime<Utc>>,
+    /// Number of failed fetches since the last success
+    pub consecutive_failures: u32,
+    /// Error of the last failed fetch
+    pub last_error: Option<String>,
+    /// An outage was announced and the recovery wasn't announced yet
+    pub alerted: bool,
+}
+
+/// A problem with a source announced to the admins
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HealthAlert {
+    /// The source failed `failures` times in a row
+    Down {
+        calendar: String,
+        failures: u32,
+        since: Option<DateTime<Utc>>,
+        error: String,
+    },
+    /// The source works again after an outage
+    Recovered {
+        calendar: String,
+        since: Option<DateTime<Utc>>,
+    },
+    /// A fetch removed too many events, the update is held back until it's confirmed
+    Quarantined {
+        calendar: String,
+        removals: usize,
+        total: usize,
+    },
+}
+
+impl Health {
+    pub const fn is_healthy(&self) -> bool {
+        self.consecutive_failures == 0
+    }
+
+    /// Records a successful fetch, returns the recovery alert if an outage was announced
+    pub fn success(&mut self, calendar: &str, date: DateTime<Utc>) -> Option<HealthAlert> {
+        let alert = self.alerted.then(|| HealthAlert::Recovered {
+            calendar: calendar.to_string(),
+            since: self.last_success,
+        });
+
+        *self = Self {
+            last_success: Some(date),
+            last_attempt: Some(date),
+            ..Default::default()
+        };
+
+        alert
+    }
+
+    /// Records a failed fetch, returns the outage alert when the source
+    /// failed `threshold` times in a row. The outage is only announced once.
+    pub fn failure(
+        &mut self,
+        calendar: &str,
+        date: DateTime<Utc>,
+        error: String,
+        threshold: u32,
+    ) -> Option<HealthAlert> {
+        self.last_attempt = Some(date);
+        self.consecutive_failures += 1;
+        self.last_error = Some(error.clone());
+
+        if self.alerted || self.consecutive_failures < threshold.max(1) {
+            return None;
+        }
+        self.alerted = true;
+
+        Some(HealthAlert::Down {
+            calendar: calendar.to_string(),
+            failures: self.consecutive_failures,
+            since: self.last_success,
+            error,
+        })
+    }
+}
+
+/// Formats the date of the last success of a source
+fn since(date: Option<DateTime<Utc>>) -> String {
+    date.map_or_else(
+        || "jamais".to_string(),
+        |date| format!("<t:{}:R>", date.timestamp()),
+    )
+}
+
+impl From<&HealthAlert> for CreateEmbed {
+    fn from(alert: &HealthAlert) -> Self {
+        match alert {
+            HealthAlert::Down {
+                calendar,
+                failures,
+                since: last_success,
+                error,
+            } => Self::default()
+                .title(format!("{calendar} est inaccessible"))
+                .color(Color::RED)
+                .description(format!(
+                    "{failures} échecs consécutifs, dernière récupération réussie: {}\n```\n{error}\n```",
+                    since(*last_success)
+                ))
+                .footer(CreateEmbedFooter::new("Source en panne")),
+            HealthAlert::Recovered {
+                calendar,
+                since: last_success,
+            } => Self::default()
+                .title(format!("{calendar} est de nouveau accessible"))
+                .color(Color::DARK_GREEN)
+                .description(format!(
+                    "Dernière récupération réussie avant la panne: {}",
+                    since(*last_success)
+                ))
+                .footer(CreateEmbedFooter::new("Source rétablie")),
+            HealthAlert::Quarantined {
+                calendar,
+                removals,
+                total,
+            } => Self::default()
+                .title(format!("Mise à jour de {calendar} en quarantaine"))
+                .color(Color::ORANGE)
+                .description(format!(
+                    "La source supprime {removals} des {total} évènements à venir. \
+                     La mise à jour sera appliquée si les prochaines récupérations la confirment, \
+                     ou avec la commande `/schedule confirm`."
+                ))
+                .footer(CreateEmbedFooter::new("Suppressions en attente")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{DateTime, Utc};
+
+    use super::{Health, HealthAlert};
+
+    fn date(timestamp: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(timestamp, 0).unwrap()
+    }
+
+    #[test]
+    fn alert_after_threshold() {
+        let mut health = Health::default();
+        health.success("test", date(0));
+
+        assert_eq!(health.failure("test", date(1), "502".to_string(), 3), None);
+        assert_eq!(health.failure("test", date(2), "502".to_string(), 3), None);
+        assert_eq!(
+            health.failure("test", date(3), "timeout".to_string(), 3),
+            Some(HealthAlert::Down {
+                calendar: "test".to_string(),
+                failures: 3,
+                since: Some(date(0)),
+                error: "timeout".to_string(),
+            })
+        );
+        // the outage is only announced once
+        assert_eq!(health.failure("test", date(4), "502".to_string(), 3), None);
+        assert_eq!(health.consecutive_failures, 4);
+
+        assert_eq!(
+            health.success("test", date(5)),
+            Some(HealthAlert::Recovered {
+                calendar: "test".to_string(),
+                since: Some(date(0)),
+            })
+        );
+        assert!(health.is_healthy());
+        assert_eq!(health.last_error, None);
+    }
+
+    #[test]
+    fn no_recovery_without_alert() {
+        let mut health = Health::default();
+
+        assert_eq!(health.failure("test", date(1), "502".to_string(), 3), None);
+        assert_eq!(health.success("test", date(2)), None);
+        assert_eq!(health.last_success, Some(date(2)));
+    }
+}
@@ -0,0 +1,240 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::cfg::Retention;
+
+use super::{Event, Field, UpdateKind, UpdateResult};
+
+/// A change of an event, as it was announced
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Change {
+    /// When the change was fetched
+    pub date: DateTime<Utc>,
+    pub calendar: String,
+    pub kind: UpdateKind,
+    /// The event before the change, `None` for a creation
+    pub before: Option<Arc<Event>>,
+    /// The event after the change, `None` for a removal
+    pub after: Option<Arc<Event>>,
+    /// Fields changed by an update
+    pub changes: Vec<Field>,
+}
+
+impl Change {
+    fn new(calendar: &str, date: DateTime<Utc>, update: &UpdateResult) -> Self {
+        let (before, after, changes) = match update {
+            UpdateResult::Created(event) => (None, Some(event.clone()), vec![]),
+            UpdateResult::Removed(event) => (Some(event.clone()), None, vec![]),
+            UpdateResult::Cancelled(event) => (None, Some(event.clone()), vec![Field::Status]),
+            UpdateResult::Updated { old, new, changes } => {
+                (Some(old.clone()), Some(new.clone()), changes.clone())
+            }
+        };
+
+        Self {
+            date,
+            calendar: calendar.to_string(),
+            kind: update.kind(),
+            before,
+            after,
+            changes,
+        }
+    }
+
+    /// Returns the latest known version of the event
+    pub fn event(&self) -> &Event {
+        self.after
+            .as_ref()
+            .or(self.before.as_ref())
+            .expect("a change has at least one version of its event")
+    }
+
+    /// Checks if the change concerns the event with the given uid.
+    /// An event whose uid changed is found with both of its uids.
+    pub fn concerns(&self, uid: &str) -> bool {
+        [&self.before, &self.after]
+            .into_iter()
+            .flatten()
+            .any(|event| event.uid == uid)
+    }
+
+    /// Checks if one of the versions of the event has a summary containing `text`, ignoring the case
+    pub fn mentions(&self, text: &str) -> bool {
+        let text = text.to_lowercase();
+
+        [&self.before, &self.after]
+            .into_iter()
+            .flatten()
+            .any(|event| event.summary.to_lowercase().contains(&text))
+    }
+}
+
+/// Changes announced for all the calendars, in chronological order
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct History {
+    changes: Vec<Change>,
+}
+
+impl History {
+    /// Appends the updates of a calendar to the history
+    pub fn record(&mut self, calendar: &str, date: DateTime<Utc>, updates: &[UpdateResult]) {
+        self.changes.extend(
+            updates
+                .iter()
+                .map(|update| Change::new(calendar, date, update)),
+        );
+    }
+
+    /// Returns the changes of a calendar fetched in `[from, to]`
+    pub fn query<'a>(
+        &'a self,
+        calendar: &'a str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> impl DoubleEndedIterator<Item = &'a Change> + 'a {
+        self.changes.iter().filter(move |change| {
+            change.calendar == calendar && change.date >= from && change.date <= to
+        })
+    }
+
+    /// Returns the changes of an event fetched in `[from, to]`
+    pub fn event<'a>(
+        &'a self,
+        calendar: &'a str,
+        uid: &'a str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> impl DoubleEndedIterator<Item = &'a Change> + 'a {
+        self.query(calendar, from, to)
+            .filter(move |change| change.concerns(uid))
+    }
+
+    /// Drops the changes older than the retention period, then the oldest changes
+    /// above the maximum number of changes kept. Returns the number of dropped changes.
+    pub fn prune(&mut self, now: DateTime<Utc>, retention: &Retention) -> usize {
+        let count = self.changes.len();
+
+        if let Some(limit) = chrono::Duration::from_std(retention.max_age)
+            .ok()
+            .and_then(|max_age| now.checked_sub_signed(max_age))
+        {
+            self.changes.retain(|change| change.date >= limit);
+        }
+        let excess = self.changes.len().saturating_sub(retention.max_changes);
+        self.changes.drain(..excess);
+
+        let dropped = count - self.changes.len();
+        if dropped > 0 {
+            debug!("dropped {dropped} changes from the history");
+        }
+        dropped
+    }
+
+    /// Lists the distinct summaries of the events in the history, sorted
+    pub fn summaries(&self) -> Vec<&str> {
+        let mut summaries: Vec<&str> = self
+            .changes
+            .iter()
+            .map(|change| change.event().summary.as_str())
+            .collect();
+        summaries.sort_unstable();
+        summaries.dedup();
+
+        summaries
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time};
+
+    use chrono::{DateTime, Duration, Utc};
+
+    use crate::{
+        calendar::{Event, UpdateKind, UpdateResult},
+        cfg::Retention,
+    };
+
+    use super::History;
+
+    fn event(uid: &str, summary: &str) -> Arc<Event> {
+        Arc::new(Event {
+            uid: uid.to_string(),
+            summary: summary.to_string(),
+            ..Default::default()
+        })
+    }
+
+    fn date(days: i64) -> DateTime<Utc> {
+        DateTime::UNIX_EPOCH + Duration::days(days)
+    }
+
+    #[test]
+    fn query_event() {
+        let mut history = History::default();
+        history.record(
+            "test",
+            date(0),
+            &[UpdateResult::Created(event("1", "Maths"))],
+        );
+        history.record(
+            "test",
+            date(1),
+            &[UpdateResult::updated(
+                event("1", "Maths"),
+                event("2", "Maths"),
+            )],
+        );
+        history.record(
+            "other",
+            date(1),
+            &[UpdateResult::Removed(event("1", "Maths"))],
+        );
+        history.record(
+            "test",
+            date(2),
+            &[UpdateResult::Removed(event("2", "Maths"))],
+        );
+
+        // the event is found with both of its uids
+        let kinds: Vec<UpdateKind> = history
+            .event("test", "1", date(0), date(2))
+            .map(|change| change.kind)
+            .collect();
+        assert_eq!(kinds, vec![UpdateKind::Created, UpdateKind::Updated]);
+
+        let kinds: Vec<UpdateKind> = history
+            .event("test", "2", date(2), date(3))
+            .map(|change| change.kind)
+            .collect();
+        assert_eq!(kinds, vec![UpdateKind::Removed]);
+    }
+
+    #[test]
+    fn retention() {
+        let mut history = History::default();
+        for day in 0..10 {
+            history.record(
+                "test",
+                date(day),
+                &[UpdateResult::Created(event(&day.to_string(), "Maths"))],
+            );
+        }
+
+        let retention = Retention {
+            max_age: time::Duration::from_hours(24 * 5),
+            max_changes: 3,
+        };
+        assert_eq!(history.prune(date(10), &retention), 7);
+        assert_eq!(
+            history
+                .query("test", date(0), date(10))
+                .map(|change| change.event().uid.as_str())
+                .collect::<Vec<_>>(),
+            vec!["7", "8", "9"]
+        );
+    }
+}
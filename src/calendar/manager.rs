@@ -2,124 +2,277 @@ use std::{collections::HashMap, sync::Arc};
 
 use anyhow::Context;
 use bytes::Buf;
-use chrono::{DateTime, NaiveDateTime, Utc};
-use futures::Future;
-use log::{debug, error, info};
-use regex::Regex;
+use chrono::{DateTime, Utc};
+use futures::{Future, StreamExt};
+use log::{error, info, warn};
+use reqwest::Client;
+use tokio::sync::RwLock;
 
-use crate::cfg::{CalendarItem, Config};
+use crate::cfg::{CalendarItem, Config, FetchPolicy};
 
-use super::{schedule::Store, Event, UpdateResult};
+use super::{
+    health::{Health, HealthAlert},
+    parser::{parse_calendar, ParseReport},
+    schedule::{Sources, Store},
+    source::{self, Fetched, SourceState},
+    Event, UpdateResult,
+};
+
+/// New cache of a source and the events fetched from it with the report of their parsing.
+/// The events are `None` when the source didn't change.
+type FetchResult = Result<(SourceState, Option<(Vec<Event>, ParseReport)>), anyhow::Error>;
+
+/// Result of the fetch of a calendar, with the date of the fetch
+type FetchOutcome = (String, DateTime<Utc>, FetchResult);
+
+/// Copy of what is needed to fetch the sources, so the manager isn't locked during the fetches
+pub struct Fetcher {
+    config: Arc<Config>,
+    clients: Arc<HashMap<String, Client>>,
+    sources: Sources,
+}
+
+impl Fetcher {
+    /// Fetches and parses the sources of all the calendars
+    pub async fn fetch(&self) -> Vec<FetchOutcome> {
+        let tasks = Manager::tasks(&self.config, &self.clients, &self.sources);
+
+        // the number of sources fetched at the same time is limited to spare the servers
+        futures::stream::iter(tasks)
+            .buffer_unordered(self.config.calendar.concurrency.max(1))
+            .collect()
+            .await
+    }
+}
 
 pub struct Manager {
     config: Arc<Config>,
+    /// Http client of each calendar, configured with the timeouts of the calendar
+    clients: Arc<HashMap<String, Client>>,
     pub store: Store,
+    /// Report of the last parsing of each calendar
+    pub reports: HashMap<String, ParseReport>,
+    /// Health of the source of each calendar
+    pub health: HashMap<String, Health>,
+    /// Outages and recoveries waiting to be announced
+    alerts: Vec<HealthAlert>,
 }
 
 impl Manager {
     pub fn new(config: Arc<Config>) -> Result<Self, anyhow::Error> {
+        let clients = config
+            .calendar
+            .calendars
+            .iter()
+            .map(|(name, watch_item)| {
+                let policy = watch_item.fetch_policy(&config.calendar);
+                let client = Client::builder()
+                    .connect_timeout(policy.connect_timeout)
+                    .read_timeout(policy.read_timeout)
+                    .build()
+                    .context("failed to build the http client")?;
+
+                Ok((name.clone(), client))
+            })
+            .collect::<Result<_, anyhow::Error>>()?;
+
         Ok(Self {
             config: config.clone(),
+            clients: Arc::new(clients),
             store: Store::new(config)?,
+            reports: HashMap::new(),
+            health: HashMap::new(),
+            alerts: Vec::new(),
         })
     }
 
     #[inline]
-    async fn fetch_task(watch_item: &CalendarItem) -> Result<Vec<Event>, anyhow::Error> {
-        let response = reqwest::get(&watch_item.source).await?.error_for_status()?;
-
-        let data = response.bytes().await?.reader();
-
-        let parser = ical::IcalParser::new(data);
-        let mut events = Vec::new();
-
-        for calendar in parser.flatten() {
-            for event in calendar.events {
-                let mut cal_event: Event = Event::default();
-
-                for property in &event.properties {
-                    if let Some(value) = &property.value {
-                        match &property.name as &str {
-                            "DTSTART" => {
-                                debug!("Parsing DTSTART: {}", value);
-                                let ndt = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%SZ")?;
-                                cal_event.start = ndt.and_utc();
-                            }
-                            "DTEND" => {
-                                debug!("Parsing DTEND: {}", value);
-                                let ndt = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%SZ")?;
-                                cal_event.end = ndt.and_utc();
-                            }
-                            "SUMMARY" => {
-                                cal_event.summary = value.trim().to_string();
-                            }
-                            "LOCATION" => {
-                                cal_event.location = value.to_string();
-                            }
-                            "DESCRIPTION" => {
-                                let re = Regex::new(r"\(.*\)")
-                                    .context("failed to build regex expression")?;
-
-                                cal_event.description =
-                                    re.replace_all(value, "").trim().to_string();
-                            }
-                            "UID" => {
-                                cal_event.uid = value.to_string();
-                            }
-                            &_ => {}
-                        }
-                    }
-                }
-
-                events.push(cal_event);
+    async fn fetch_task(
+        client: &Client,
+        watch_item: &CalendarItem,
+        policy: &FetchPolicy,
+        mut state: SourceState,
+    ) -> FetchResult {
+        let data = match source::fetch_with_policy(client, watch_item, policy, &mut state).await? {
+            Fetched::Unchanged => {
+                info!("{} didn't change since the last fetch", watch_item.source);
+                return Ok((state, None));
             }
-        }
+            Fetched::Body(data) => data.reader(),
+        };
+
+        let (events, report) = parse_calendar(data, watch_item)?;
 
         info!("Fetched {} events from {}", events.len(), watch_item.source);
 
-        Ok(events)
+        state.parsed = Some(report.date);
+        state.recurring = report.recurring > 0;
+        state.current = true;
+
+        Ok((state, Some((events, report))))
     }
 
     #[inline]
-    fn tasks(
-        config: &Config,
-    ) -> impl Iterator<
-        Item = impl Future<Output = (String, DateTime<Utc>, Result<Vec<Event>, anyhow::Error>)> + '_,
-    > {
+    fn tasks<'a>(
+        config: &'a Config,
+        clients: &'a HashMap<String, Client>,
+        sources: &'a Sources,
+    ) -> impl Iterator<Item = impl Future<Output = FetchOutcome> + 'a> {
         config
             .calendar
             .calendars
             .iter()
-            .map(|(name, object)| async move {
-                let result = Self::fetch_task(object).await;
+            .map(move |(name, object)| async move {
+                let state = sources.get(name).cloned().unwrap_or_default();
+                let policy = object.fetch_policy(&config.calendar);
+                // the clients are built from the same configuration
+                let result = Self::fetch_task(&clients[name], object, policy, state).await;
                 (name.to_string(), Utc::now(), result)
             })
     }
 
+    /// Copies the state of the sources to fetch them without holding the manager
+    pub fn fetcher(&self) -> Fetcher {
+        Fetcher {
+            config: self.config.clone(),
+            clients: self.clients.clone(),
+            sources: self.store.sources.clone(),
+        }
+    }
+
     #[allow(unused)]
     pub async fn update_calendars(
         &mut self,
     ) -> Result<HashMap<std::string::String, Vec<UpdateResult>>, anyhow::Error> {
-        let data = {
-            let tasks = Self::tasks(&self.config);
-            let data = futures_util::future::join_all(tasks).await;
+        let data = self.fetcher().fetch().await;
+        let (updates, changed) = self.apply_fetched(data)?;
 
-            data
-        };
+        self.announce(updates, changed, Utc::now())
+    }
+
+    /// Fetches the sources of a shared manager.
+    /// The lock is only held to copy the state of the sources and to apply the fetched events,
+    /// so the commands can still read the calendars while the sources are slow to answer.
+    pub async fn update_shared(
+        manager: &RwLock<Self>,
+    ) -> Result<HashMap<String, Vec<UpdateResult>>, anyhow::Error> {
+        let fetcher = manager.read().await.fetcher();
+        let data = fetcher.fetch().await;
+
+        let mut manager = manager.write().await;
+        let (updates, changed) = manager.apply_fetched(data)?;
+        manager.announce(updates, changed, Utc::now())
+    }
+
+    /// Applies the quarantined update of a calendar, confirmed by an admin.
+    /// Returns the updates to announce.
+    pub fn confirm(
+        &mut self,
+        calendar: &str,
+    ) -> Result<HashMap<String, Vec<UpdateResult>>, anyhow::Error> {
+        let updates = self.store.confirm(calendar)?;
+
+        self.announce(
+            HashMap::from([(calendar.to_string(), updates)]),
+            true,
+            Utc::now(),
+        )
+    }
+
+    /// Adds the updates to the history.
+    /// The database is written once, if the calendars changed or updates were recorded.
+    fn announce(
+        &mut self,
+        updates: HashMap<String, Vec<UpdateResult>>,
+        changed: bool,
+        now: DateTime<Utc>,
+    ) -> Result<HashMap<String, Vec<UpdateResult>>, anyhow::Error> {
+        // the history is an audit log, the updates that won't be announced are kept too
+        let recorded = updates.values().any(|updates| !updates.is_empty());
+        if recorded {
+            self.store.record(&updates, now);
+        }
+
+        if changed || recorded {
+            self.store
+                .persist()
+                .context("failed to save the database")?;
+        }
+
+        Ok(updates)
+    }
+
+    /// Applies the events fetched from the sources to the calendars.
+    /// Returns the updates and whether the store changed, it isn't saved yet.
+    fn apply_fetched(
+        &mut self,
+        data: Vec<FetchOutcome>,
+    ) -> Result<(HashMap<String, Vec<UpdateResult>>, bool), anyhow::Error> {
         let store = &mut self.store;
 
         let mut calendars = HashMap::new();
+        let mut sources_changed = false;
 
         for (calendar_name, fetch_date, result) in data {
+            let health = self.health.entry(calendar_name.clone()).or_default();
+            let alert = match &result {
+                Ok(_) => health.success(&calendar_name, fetch_date),
+                Err(err) => health.failure(
+                    &calendar_name,
+                    fetch_date,
+                    format!("{err:#}"),
+                    self.config.calendar.alert_after,
+                ),
+            };
+            self.alerts.extend(alert);
+
             match result {
-                Ok(cal) => {
-                    info!("updating calendar {} with {} events", calendar_name, cal.len());
-                    calendars.insert(
-                        calendar_name.clone(),
-                        store
-                            .apply(&calendar_name, cal, fetch_date)
-                            .context("failed to update calendar")?,
+                Ok((state, None)) => {
+                    store.sources.insert(calendar_name.clone(), state);
+                    sources_changed = true;
+
+                    let updates = store.tick(&calendar_name, fetch_date);
+                    if !updates.is_empty() {
+                        calendars.insert(calendar_name, updates);
+                    }
+                }
+                Ok((state, Some((cal, report)))) => {
+                    if !report.issues.is_empty() {
+                        warn!(
+                            "skipped {} malformed events in calendar {}",
+                            report.issues.len(),
+                            calendar_name
+                        );
+                    }
+                    self.reports.insert(calendar_name.clone(), report);
+
+                    info!(
+                        "updating calendar {} with {} events",
+                        calendar_name,
+                        cal.len()
                     );
+                    let Some(updates) = store
+                        .apply(&calendar_name, cal, fetch_date)
+                        .context("failed to update calendar")?
+                    else {
+                        // the source state isn't saved, so the next fetch reads the source
+                        // again and can confirm the quarantined update
+                        let quarantine = &store.quarantine[&calendar_name];
+                        if quarantine.fetches == 1 {
+                            self.alerts.push(HealthAlert::Quarantined {
+                                calendar: calendar_name.clone(),
+                                removals: quarantine.removals,
+                                total: quarantine.total,
+                            });
+                        }
+                        continue;
+                    };
+
+                    store.sources.insert(calendar_name.clone(), state);
+                    sources_changed = true;
+                    if !updates.is_empty() {
+                        calendars.insert(calendar_name, updates);
+                    }
                 }
                 Err(err) => {
                     error!(
@@ -130,6 +283,403 @@ impl Manager {
             }
         }
 
-        Ok(calendars)
+        Ok((calendars, sources_changed))
+    }
+
+    /// Returns the alerts for the admins that weren't announced yet
+    pub fn take_alerts(&mut self) -> Vec<HealthAlert> {
+        std::mem::take(&mut self.alerts)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, fmt::Write, fs, path::Path, sync::Arc, time};
+
+    use chrono::{Duration, Utc};
+    use tempfile::TempDir;
+    use tokio::sync::RwLock;
+
+    use crate::{
+        calendar::{health::HealthAlert, significant_updates, Field, UpdateKind, UpdateResult},
+        cfg::{CalendarConfig, CalendarItem, Config, Creations, Notify, Settle, StorageConfig},
+    };
+
+    use super::Manager;
+
+    fn item(source: &Path) -> CalendarItem {
+        CalendarItem {
+            source: format!("file://{}", source.display()),
+            time_amount: "2w".to_string(),
+            // the tests check the updates of the first import
+            creations: Creations {
+                announce_bootstrap: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn manager(dir: &TempDir, source: &Path) -> Manager {
+        manager_with(dir, item(source))
+    }
+
+    fn manager_with(dir: &TempDir, calendar: CalendarItem) -> Manager {
+        let config = Config {
+            calendar: CalendarConfig {
+                calendars: HashMap::from([("test".to_string(), calendar)]),
+                ..Default::default()
+            },
+            storage: StorageConfig {
+                path: dir.path().join("db").display().to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        Manager::new(Arc::new(config)).unwrap()
+    }
+
+    fn ics(events: &[(&str, &str)]) -> String {
+        let start = Utc::now() + Duration::days(1);
+        let mut ics = "BEGIN:VCALENDAR\nVERSION:2.0\n".to_string();
+
+        for (uid, summary) in events {
+            write!(
+                ics,
+                "BEGIN:VEVENT\nUID:{uid}\nSUMMARY:{summary}\nDTSTART:{}\nDTEND:{}\nEND:VEVENT\n",
+                start.format("%Y%m%dT%H0000Z"),
+                (start + Duration::hours(1)).format("%Y%m%dT%H0000Z"),
+            )
+            .unwrap();
+        }
+
+        ics + "END:VCALENDAR\n"
+    }
+
+    #[tokio::test]
+    async fn shared_manager() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("calendar.ics");
+        fs::write(&file, ics(&[("1", "Maths")])).unwrap();
+        let manager = RwLock::new(manager(&dir, &file));
+
+        // the calendars stay readable while the sources are fetched
+        let fetcher = manager.read().await.fetcher();
+        let reader = manager.read().await;
+        let data = fetcher.fetch().await;
+        assert!(!reader.store.data.contains_key("test"));
+        drop(reader);
+
+        let (updates, changed) = manager.write().await.apply_fetched(data).unwrap();
+        assert!(changed);
+        assert!(matches!(&updates["test"][..], [UpdateResult::Created(_)]));
+
+        fs::write(&file, ics(&[("1", "Maths"), ("2", "Physique")])).unwrap();
+        let updates = Manager::update_shared(&manager).await.unwrap();
+        assert!(matches!(&updates["test"][..], [UpdateResult::Created(event)] if event.uid == "2"));
+        assert!(manager.read().await.store.data["test"].get("2").is_some());
+    }
+
+    #[tokio::test]
+    async fn one_write_per_cycle() {
+        let dir = TempDir::new().unwrap();
+        let first = dir.path().join("first.ics");
+        let second = dir.path().join("second.ics");
+        fs::write(&first, ics(&[("1", "Maths")])).unwrap();
+        fs::write(&second, ics(&[("2", "Physique")])).unwrap();
+        let config = Config {
+            calendar: CalendarConfig {
+                calendars: HashMap::from([
+                    ("first".to_string(), item(&first)),
+                    ("second".to_string(), item(&second)),
+                ]),
+                ..Default::default()
+            },
+            storage: StorageConfig {
+                path: dir.path().join("db").display().to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut manager = Manager::new(Arc::new(config)).unwrap();
+        let snapshot = |index: usize| dir.path().join(format!("db.{index}")).exists();
+
+        let updates = manager.update_calendars().await.unwrap();
+        assert_eq!(updates.len(), 2);
+        assert!(!snapshot(1));
+
+        // each cycle rotates the snapshots once
+        manager.update_calendars().await.unwrap();
+        assert!(snapshot(1));
+        assert!(!snapshot(2));
+    }
+
+    #[tokio::test]
+    async fn file_source() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("calendar.ics");
+        fs::write(&file, ics(&[("1", "Maths")])).unwrap();
+        let mut manager = manager(&dir, &file);
+
+        let updates = manager.update_calendars().await.unwrap();
+        assert!(
+            matches!(&updates["test"][..], [UpdateResult::Created(event)] if event.summary == "Maths")
+        );
+
+        // the file didn't change
+        let updates = manager.update_calendars().await.unwrap();
+        assert!(updates.is_empty());
+
+        fs::write(&file, ics(&[("1", "Physique")])).unwrap();
+        let updates = manager.update_calendars().await.unwrap();
+        assert!(
+            matches!(&updates["test"][..], [UpdateResult::Updated { new, .. }] if new.summary == "Physique")
+        );
+    }
+
+    #[tokio::test]
+    async fn directory_source() {
+        let dir = TempDir::new().unwrap();
+        let calendars = dir.path().join("calendars");
+        fs::create_dir(&calendars).unwrap();
+        fs::write(calendars.join("a.ics"), ics(&[("1", "Maths")])).unwrap();
+        fs::write(calendars.join("b.ics"), ics(&[("2", "Physique")])).unwrap();
+        // not an ics file
+        fs::write(calendars.join("notes.txt"), "BEGIN:VCALENDAR").unwrap();
+        let mut manager = manager(&dir, &calendars);
+
+        let updates = manager.update_calendars().await.unwrap();
+        assert_eq!(updates["test"].len(), 2);
+        assert!(manager.reports["test"].issues.is_empty());
+
+        fs::remove_file(calendars.join("b.ics")).unwrap();
+        let updates = manager.update_calendars().await.unwrap();
+        assert!(matches!(&updates["test"][..], [UpdateResult::Removed(event)] if event.uid == "2"));
+    }
+
+    #[tokio::test]
+    async fn persisted_history() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("calendar.ics");
+        fs::write(&file, ics(&[("1", "Maths")])).unwrap();
+        let mut manager = manager(&dir, &file);
+        manager.update_calendars().await.unwrap();
+
+        fs::write(&file, ics(&[("1", "Physique")])).unwrap();
+        manager.update_calendars().await.unwrap();
+
+        // the history is read back from the db
+        let reopened = self::manager(&dir, &file);
+        let changes: Vec<UpdateKind> = reopened
+            .store
+            .history
+            .event("test", "1", Utc::now() - Duration::hours(1), Utc::now())
+            .map(|change| change.kind)
+            .collect();
+        assert_eq!(changes, vec![UpdateKind::Created, UpdateKind::Updated]);
+    }
+
+    #[test]
+    fn migrate_legacy_database() {
+        let dir = TempDir::new().unwrap();
+        // an empty database in the first layout, without header
+        fs::write(dir.path().join("db"), [0]).unwrap();
+
+        manager(&dir, Path::new("calendar.ics"));
+
+        assert!(dir.path().join("db.v0").exists());
+        assert!(fs::read(dir.path().join("db"))
+            .unwrap()
+            .starts_with(b"TMDB"));
+    }
+
+    #[tokio::test]
+    async fn quarantine_mass_removal() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("calendar.ics");
+        fs::write(
+            &file,
+            ics(&[
+                ("1", "Maths"),
+                ("2", "Physique"),
+                ("3", "Chimie"),
+                ("4", "Anglais"),
+            ]),
+        )
+        .unwrap();
+        let mut manager = manager(&dir, &file);
+        manager.update_calendars().await.unwrap();
+
+        // the source is truncated, the removals are held back
+        fs::write(&file, ics(&[])).unwrap();
+        let updates = manager.update_calendars().await.unwrap();
+        assert!(updates.is_empty());
+        assert_eq!(
+            manager.take_alerts(),
+            vec![HealthAlert::Quarantined {
+                calendar: "test".to_string(),
+                removals: 4,
+                total: 4,
+            }]
+        );
+        assert_eq!(
+            manager.store.data["test"]
+                .get_range(Utc::now(), Duration::weeks(2))
+                .len(),
+            4
+        );
+
+        // the next fetch confirms the removals
+        let updates = manager.update_calendars().await.unwrap();
+        assert_eq!(updates["test"].len(), 4);
+        assert!(manager.store.quarantine.is_empty());
+    }
+
+    #[tokio::test]
+    async fn settle_flapping_changes() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("calendar.ics");
+        fs::write(&file, ics(&[("1", "Maths")])).unwrap();
+        let mut manager = manager_with(
+            &dir,
+            CalendarItem {
+                settle: Settle {
+                    cycles: 1,
+                    ..Default::default()
+                },
+                ..item(&file)
+            },
+        );
+
+        // the first version of the event settles on the next fetch
+        assert!(manager.update_calendars().await.unwrap().is_empty());
+        let updates = manager.update_calendars().await.unwrap();
+        assert!(matches!(&updates["test"][..], [UpdateResult::Created(_)]));
+
+        // the change is reverted before it settles
+        fs::write(&file, ics(&[("1", "Physique")])).unwrap();
+        assert!(manager.update_calendars().await.unwrap().is_empty());
+        fs::write(&file, ics(&[("1", "Maths")])).unwrap();
+        assert!(manager.update_calendars().await.unwrap().is_empty());
+        assert!(manager.update_calendars().await.unwrap().is_empty());
+
+        // the change lasts, it's published on the next fetch
+        fs::write(&file, ics(&[("1", "Chimie")])).unwrap();
+        assert!(manager.update_calendars().await.unwrap().is_empty());
+        let updates = manager.update_calendars().await.unwrap();
+        assert!(
+            matches!(&updates["test"][..], [UpdateResult::Updated { old, new, changes }] if old.summary == "Maths" && new.summary == "Chimie" && changes == &[Field::Summary])
+        );
+    }
+
+    #[tokio::test]
+    async fn persisted_pending_changes() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("calendar.ics");
+        fs::write(&file, ics(&[("1", "Maths")])).unwrap();
+        let calendar = CalendarItem {
+            settle: Settle {
+                cycles: 1,
+                ..Default::default()
+            },
+            ..item(&file)
+        };
+
+        let mut manager = manager_with(&dir, calendar.clone());
+        assert!(manager.update_calendars().await.unwrap().is_empty());
+        drop(manager);
+
+        // the change waiting for its settle period survives a restart
+        let mut manager = manager_with(&dir, calendar);
+        let updates = manager.update_calendars().await.unwrap();
+        assert!(matches!(&updates["test"][..], [UpdateResult::Created(_)]));
+    }
+
+    #[tokio::test]
+    async fn refill_emptied_calendar() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("calendar.ics");
+        fs::write(&file, ics(&[("1", "Maths"), ("2", "Physique")])).unwrap();
+        let mut manager = manager_with(
+            &dir,
+            CalendarItem {
+                creations: Creations::default(),
+                ..item(&file)
+            },
+        );
+
+        // the first import is silent
+        assert!(manager.update_calendars().await.unwrap().is_empty());
+
+        fs::write(&file, ics(&[])).unwrap();
+        let updates = manager.update_calendars().await.unwrap();
+        assert!(matches!(
+            &updates["test"][..],
+            [UpdateResult::Removed(_), UpdateResult::Removed(_)]
+        ));
+        assert!(manager.store.data["test"].get("1").is_none());
+
+        // the calendar is known: its new events are announced
+        fs::write(&file, ics(&[("3", "Chimie")])).unwrap();
+        let updates = manager.update_calendars().await.unwrap();
+        assert!(matches!(&updates["test"][..], [UpdateResult::Created(event)] if event.uid == "3"));
+    }
+
+    #[tokio::test]
+    async fn notify_filter() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("calendar.ics");
+        fs::write(&file, ics(&[("1", "Maths"), ("2", "Physique")])).unwrap();
+        let mut manager = manager_with(
+            &dir,
+            CalendarItem {
+                notify: Notify {
+                    kinds: vec![UpdateKind::Updated, UpdateKind::Removed],
+                    fields: vec![Field::Summary, Field::Start, Field::Location],
+                    lookahead: Some(time::Duration::from_hours(12)),
+                },
+                ..item(&file)
+            },
+        );
+        let config = manager.config.clone();
+
+        // the creations aren't announced
+        let updates = manager.update_calendars().await.unwrap();
+        assert_eq!(updates["test"].len(), 2);
+        assert!(significant_updates(&config, updates, Utc::now()).is_empty());
+
+        let mut edited = ics(&[("1", "Chimie"), ("2", "Physique")]);
+        edited = edited.replace("UID:2\n", "UID:2\nDESCRIPTION:typo\n");
+        fs::write(&file, edited).unwrap();
+
+        // the description edit is ignored, and the events start tomorrow:
+        // beyond the lookahead
+        let updates = manager.update_calendars().await.unwrap();
+        assert_eq!(updates["test"].len(), 2);
+        assert!(significant_updates(&config, updates, Utc::now()).is_empty());
+
+        // the history keeps every change, even the ones that weren't announced
+        let now = Utc::now();
+        let change = manager
+            .store
+            .history
+            .event("test", "2", now - Duration::hours(1), now)
+            .last()
+            .unwrap();
+        assert_eq!(change.kind, UpdateKind::Updated);
+        assert_eq!(change.changes, vec![Field::Description]);
+
+        let updates = HashMap::from([(
+            "test".to_string(),
+            vec![UpdateResult::updated(
+                manager.store.data["test"].get("1").unwrap().clone(),
+                manager.store.data["test"].get("2").unwrap().clone(),
+            )],
+        )]);
+        let updates = significant_updates(&config, updates, now + Duration::hours(20));
+        assert!(
+            matches!(&updates["test"][..], [UpdateResult::Updated { new, .. }] if new.summary == "Physique")
+        );
     }
 }
@@ -1,22 +1,159 @@
 use std::{collections::HashMap, sync::Arc};
 
 use anyhow::Context;
-use chrono::{DateTime, Datelike, Timelike, Utc};
+use chrono::{DateTime, Datelike, Duration, Timelike, Utc};
 use log::{debug, error, info};
 use poise::serenity_prelude::{Color, CreateEmbed, CreateEmbedFooter, CreateMessage, Http};
 use serde::{Deserialize, Serialize};
 use tokio::time::sleep;
 
-use crate::bot::Bot;
+use crate::{
+    bot::Bot,
+    cfg::{Config, Notify},
+};
 
+pub mod ade;
+pub mod caldav;
+pub mod database;
+pub mod health;
+pub mod history;
 pub mod manager;
+pub mod parser;
+pub mod recurrence;
 pub mod schedule;
+pub mod settle;
+pub mod source;
+pub mod time;
 
 #[derive(PartialEq, Eq, Debug)]
 pub enum UpdateResult {
     Created(Arc<Event>),
-    Updated { old: Arc<Event>, new: Arc<Event> },
+    /// The fields of an existing event changed, `changes` lists them
+    Updated {
+        old: Arc<Event>,
+        new: Arc<Event>,
+        changes: Vec<Field>,
+    },
     Removed(Arc<Event>),
+    /// An existing event was marked as cancelled by the source (it's still in the source)
+    Cancelled(Arc<Event>),
+}
+
+impl UpdateResult {
+    /// Builds the update of an event, with the fields changed between the two versions
+    pub fn updated(old: Arc<Event>, new: Arc<Event>) -> Self {
+        let changes = old.changes(&new);
+        Self::Updated { old, new, changes }
+    }
+
+    pub const fn kind(&self) -> UpdateKind {
+        match self {
+            Self::Created(_) => UpdateKind::Created,
+            Self::Updated { .. } => UpdateKind::Updated,
+            Self::Removed(_) => UpdateKind::Removed,
+            Self::Cancelled(_) => UpdateKind::Cancelled,
+        }
+    }
+
+    /// Checks if the update is worth a notification according to the settings of its calendar.
+    /// Updated events are announced if one of their changed fields is followed, and
+    /// the lookahead keeps the changes of the events (before or after the update) starting soon.
+    pub fn is_significant(&self, notify: &Notify, now: DateTime<Utc>) -> bool {
+        if !notify.kinds.contains(&self.kind()) {
+            return false;
+        }
+
+        let starts = match self {
+            Self::Created(event) | Self::Removed(event) | Self::Cancelled(event) => {
+                vec![event.start]
+            }
+            Self::Updated { old, new, changes } => {
+                if !changes.iter().any(|field| notify.fields.contains(field)) {
+                    return false;
+                }
+                vec![old.start, new.start]
+            }
+        };
+
+        let Some(horizon) = notify
+            .lookahead
+            .and_then(|lookahead| Duration::from_std(lookahead).ok())
+            .and_then(|lookahead| now.checked_add_signed(lookahead))
+        else {
+            return true;
+        };
+        starts.into_iter().any(|start| start < horizon)
+    }
+}
+
+/// Kind of an update, used to choose the updates announced by a calendar
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpdateKind {
+    Created,
+    Updated,
+    Removed,
+    Cancelled,
+}
+
+/// A field of an event that can change between two fetches
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Field {
+    Summary,
+    /// The start of the event, or the event switched between timed and all-day
+    Start,
+    /// The end of the event, or the event switched between timed and all-day
+    End,
+    Location,
+    Description,
+    Status,
+}
+
+impl Field {
+    /// Name of the field in the embeds
+    pub const fn label(self) -> &'static str {
+        match self {
+            Self::Summary => "Titre",
+            Self::Start => "Début",
+            Self::End => "Fin",
+            Self::Location => "Emplacement",
+            Self::Description => "Description",
+            Self::Status => "Statut",
+        }
+    }
+}
+
+impl UpdateKind {
+    /// Name of the kind of update in the embeds
+    pub const fn label(self) -> &'static str {
+        match self {
+            Self::Created => "Ajouté",
+            Self::Updated => "Mis à jour",
+            Self::Removed => "Supprimé",
+            Self::Cancelled => "Annulé",
+        }
+    }
+}
+
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy, Serialize, Deserialize)]
+/// Status of an event (`STATUS` property of the VEVENT)
+pub enum Status {
+    Tentative,
+    #[default]
+    Confirmed,
+    Cancelled,
+}
+
+impl Status {
+    /// Name of the status in the embeds
+    pub const fn label(self) -> &'static str {
+        match self {
+            Self::Tentative => "Provisoire",
+            Self::Confirmed => "Confirmé",
+            Self::Cancelled => "Annulé",
+        }
+    }
 }
 
 #[derive(Debug, Default, Eq, PartialEq, Clone, Serialize, Deserialize)]
@@ -35,6 +172,71 @@ pub struct Event {
     pub description: String,
     /// Unique id of the event.
     pub uid: String,
+    /// The event covers whole days (`VALUE=DATE`).
+    /// The start and end are then aligned on midnight utc and the end is exclusive.
+    pub all_day: bool,
+    /// Status of the event, cancelled events are kept in the calendar.
+    pub status: Status,
+}
+
+impl Event {
+    pub fn is_cancelled(&self) -> bool {
+        self.status == Status::Cancelled
+    }
+
+    /// Lists the fields that differ in the `new` version of the event.
+    /// The uid isn't compared, both versions are expected to describe the same event.
+    pub fn changes(&self, new: &Self) -> Vec<Field> {
+        let timing = self.all_day != new.all_day;
+
+        [
+            (Field::Summary, self.summary != new.summary),
+            (Field::Start, timing || self.start != new.start),
+            (Field::End, timing || self.end != new.end),
+            (Field::Location, self.location != new.location),
+            (Field::Description, self.description != new.description),
+            (Field::Status, self.status != new.status),
+        ]
+        .into_iter()
+        .filter_map(|(field, changed)| changed.then_some(field))
+        .collect()
+    }
+
+    /// Formats the description of the event as a code block for the embeds.
+    /// The block starts with a new line so the first line isn't taken for a language name.
+    pub fn description_block(&self) -> String {
+        let description = self.description.trim();
+
+        if description.is_empty() {
+            String::new()
+        } else {
+            format!("```\n{description}\n```")
+        }
+    }
+
+    /// Formats the time span of the event for the embeds.
+    /// Timed events use discord timestamps while all-day events only show the dates.
+    pub fn time_span(&self) -> String {
+        if self.all_day {
+            let last_day = (self.end - Duration::days(1)).max(self.start);
+
+            if last_day.date_naive() == self.start.date_naive() {
+                format!("Le {}", self.start.format("%d/%m/%Y"))
+            } else {
+                format!(
+                    "Du {} au {}",
+                    self.start.format("%d/%m/%Y"),
+                    last_day.format("%d/%m/%Y")
+                )
+            }
+        } else {
+            format!(
+                "<t:{}> à <t:{}>",
+                self.start.timestamp(),
+                self.end.timestamp()
+            )
+        }
+    }
 }
 
 impl From<&UpdateResult> for CreateEmbed {
@@ -44,75 +246,96 @@ impl From<&UpdateResult> for CreateEmbed {
                 UpdateResult::Created(_) => Color::DARK_GREEN,
                 UpdateResult::Updated { .. } => Color::BLUE,
                 UpdateResult::Removed(_) => Color::RED,
+                UpdateResult::Cancelled(_) => Color::ORANGE,
             })
             .footer(CreateEmbedFooter::new(match event {
                 UpdateResult::Created(_) => "Évènement ajouté",
                 UpdateResult::Updated { .. } => "Évènement mis à jour",
                 UpdateResult::Removed(_) => "Évènement supprimé",
+                UpdateResult::Cancelled(_) => "Évènement annulé",
             }))
             .title(match &event {
-                UpdateResult::Created(event) | UpdateResult::Removed(event) => {
-                    event.summary.clone()
-                }
+                UpdateResult::Created(event)
+                | UpdateResult::Removed(event)
+                | UpdateResult::Cancelled(event) => event.summary.clone(),
 
                 // En cas de changement
-                UpdateResult::Updated { old, new } => {
-                    if old.summary == new.summary {
-                        new.summary.clone()
-                    } else {
+                UpdateResult::Updated { old, new, changes } => {
+                    if changes.contains(&Field::Summary) {
                         format!("{} => {}", old.summary, new.summary)
+                    } else {
+                        new.summary.clone()
                     }
                 }
             })
             .description(match &event {
-                UpdateResult::Created(event) | UpdateResult::Removed(event) => format!(
-                    "<t:{}> à <t:{}>\n`{}`",
-                    event.start.timestamp(),
-                    event.end.timestamp(),
-                    event.description.replace("\\n", " ")
-                ),
-                UpdateResult::Updated { old, new } => {
+                UpdateResult::Created(event)
+                | UpdateResult::Removed(event)
+                | UpdateResult::Cancelled(event) => {
+                    format!("{}\n{}", event.time_span(), event.description_block())
+                }
+                UpdateResult::Updated { old, new, changes } => {
                     format!(
                         "{}\n{}",
-                        if old.start != new.start || old.end != new.end {
+                        if changes.contains(&Field::Start) || changes.contains(&Field::End) {
                             format!(
-                                "Anciennement de <t:{}> à <t:{}> \n
-                                 désormais    de <t:{}> à <t:{}>",
-                                old.start.timestamp(),
-                                old.end.timestamp(),
-                                new.start.timestamp(),
-                                new.end.timestamp()
+                                "Anciennement: {} \n
+                                 désormais:    {}",
+                                old.time_span(),
+                                new.time_span()
                             )
                         } else {
-                            format!(
-                                "De <t:{}> à <t:{}>",
-                                new.start.timestamp(),
-                                new.end.timestamp()
-                            )
+                            new.time_span()
                         },
-                        format!("```{}```", new.description)
+                        if changes.contains(&Field::Description)
+                            && !new.description.trim().is_empty()
+                        {
+                            format!("Description modifiée:\n{}", new.description_block())
+                        } else {
+                            new.description_block()
+                        }
                     )
                 }
             });
 
         f = match event {
-            UpdateResult::Created(event) | UpdateResult::Removed(event) => {
+            UpdateResult::Created(event)
+            | UpdateResult::Removed(event)
+            | UpdateResult::Cancelled(event) => {
                 if !event.location.is_empty() {
                     f.field("Emplacement", &event.location, true)
                 } else {
                     f
                 }
             }
-            UpdateResult::Updated { old, new } => {
-                if !old.location.is_empty() || !new.location.is_empty() {
-                    f.field(
+            UpdateResult::Updated { old, new, changes } => {
+                if changes.contains(&Field::Location) {
+                    f = f.field(
                         "Emplacement",
-                        format!("A été déplacé vers`{}`", new.location),
+                        if old.location.is_empty() {
+                            format!("A été déplacé vers `{}`", new.location)
+                        } else if new.location.is_empty() {
+                            format!("N'a plus lieu en `{}`", old.location)
+                        } else {
+                            format!(
+                                "A été déplacé de `{}` vers `{}`",
+                                old.location, new.location
+                            )
+                        },
                         true,
-                    )
-                } else {
-                    f
+                    );
+                } else if !new.location.is_empty() {
+                    f = f.field("Emplacement", &new.location, true);
+                }
+
+                if changes.contains(&Field::Status) {
+                    f = f.field(
+                        "Statut",
+                        format!("{} => {}", old.status.label(), new.status.label()),
+                        true,
+                    );
                 }
+                f
             }
         };
 
@@ -164,11 +387,16 @@ impl From<&Event> for CreateEmbed {
         #[allow(clippy::cast_possible_truncation)]
         let color = hsl_to_rgb(h as u32, 0.75f64, 1f64 - l);
 
-        f = f.title(&event.summary).color(color).description(format!(
-            "<t:{}> à <t:{}>\n`{}`",
-            event.start.timestamp(),
-            event.end.timestamp(),
-            event.description.replace("\\n", " ")
+        let title = if event.is_cancelled() {
+            format!("{} (annulé)", event.summary)
+        } else {
+            event.summary.clone()
+        };
+
+        f = f.title(title).color(color).description(format!(
+            "{}\n{}",
+            event.time_span(),
+            event.description_block()
         ));
 
         if !event.location.is_empty() {
@@ -177,19 +405,41 @@ impl From<&Event> for CreateEmbed {
         f
     }
 }
-async fn process_events(
-    bot: Arc<Bot>,
+
+/// Keeps the updates worth a notification, according to the `notify` settings of each calendar
+pub fn significant_updates(
+    config: &Config,
+    updates_map: HashMap<String, Vec<UpdateResult>>,
+    now: DateTime<Utc>,
+) -> HashMap<String, Vec<UpdateResult>> {
+    updates_map
+        .into_iter()
+        .filter_map(|(calendar_name, updates)| {
+            let notify = &config.calendar.calendars.get(&calendar_name)?.notify;
+            let updates: Vec<UpdateResult> = updates
+                .into_iter()
+                .filter(|update| {
+                    let significant = update.is_significant(notify, now);
+                    if !significant {
+                        debug!("not announcing an update of {calendar_name}: {update:?}");
+                    }
+                    significant
+                })
+                .collect();
+
+            (!updates.is_empty()).then_some((calendar_name, updates))
+        })
+        .collect()
+}
+
+/// Announces the updates of the calendars in their channels
+pub async fn process_events(
+    config: &Config,
     updates_map: HashMap<String, Vec<UpdateResult>>,
     http: Arc<Http>,
 ) {
     for (calendar_name, updates) in updates_map {
-        let calendar = bot
-            .data
-            .config
-            .calendar
-            .calendars
-            .get(&calendar_name)
-            .unwrap();
+        let calendar = config.calendar.calendars.get(&calendar_name).unwrap();
 
         for channel in &calendar.channel {
             let embeds: Vec<CreateEmbed> = updates.iter().map(Into::into).collect();
@@ -216,6 +466,22 @@ async fn process_events(
     }
 }
 
+/// Announces the outages and recoveries of the sources in the admin channel
+async fn process_alerts(bot: Arc<Bot>, http: Arc<Http>) {
+    let alerts = bot.data.calendar_manager.write().await.take_alerts();
+    let Some(channel) = bot.data.config.calendar.alert_channel else {
+        return;
+    };
+
+    for chunk in alerts.chunks(10) {
+        let message = CreateMessage::default().add_embeds(chunk.iter().map(Into::into).collect());
+
+        if let Err(err) = channel.send_message(http.clone(), message).await {
+            error!("failed to send the source alerts to the channel {channel}: {err}");
+        }
+    }
+}
+
 pub async fn manager_task(bot: Arc<Bot>, http: Arc<Http>) -> Result<(), anyhow::Error> {
     // parse the cron expression to a saffon cron expression
     let schedule = saffron::Cron::new(match bot.data.config.calendar.refetch.parse() {
@@ -228,12 +494,8 @@ pub async fn manager_task(bot: Arc<Bot>, http: Arc<Http>) -> Result<(), anyhow::
     let mut shutdown = bot.shutdown.resubscribe();
 
     // update calendars at the start to ensure availability on startup
-    bot.data
-        .calendar_manager
-        .write()
-        .await
-        .update_calendars()
-        .await?;
+    manager::Manager::update_shared(&bot.data.calendar_manager).await?;
+    process_alerts(bot.clone(), http.clone()).await;
 
     loop {
         // calculate the next cron execution and wait
@@ -255,9 +517,11 @@ pub async fn manager_task(bot: Arc<Bot>, http: Arc<Http>) -> Result<(), anyhow::
         );
         tokio::select! {
             _ = wait => {
-                let updates = bot.data.calendar_manager.write().await.update_calendars().await?;
+                let updates = manager::Manager::update_shared(&bot.data.calendar_manager).await?;
                 debug!("got updates: {:#?}", updates);
-                process_events(bot.clone(), updates, http.clone()).await;
+                let updates = significant_updates(&bot.data.config, updates, Utc::now());
+                process_events(&bot.data.config, updates, http.clone()).await;
+                process_alerts(bot.clone(), http.clone()).await;
             },
             _ = shutdown.recv() => {
                 return Ok(());
@@ -0,0 +1,615 @@
+use std::{borrow::Cow, io::BufRead};
+
+use chrono::{DateTime, Duration, Utc};
+use chrono_tz::Tz;
+use ical::{parser::ical::component::IcalEvent, property::Property};
+use log::{debug, info, warn};
+
+use crate::cfg::{CalendarItem, CleanupRule, TextField};
+
+use super::{
+    recurrence::{apply_overrides, occurrence_uid, Recurrence},
+    time::{parse_duration, TimeContext, TimeValue},
+    Event, Status,
+};
+
+/// A problem found while parsing an event of a source
+#[derive(Debug, Clone)]
+pub struct ParseIssue {
+    /// Position of the VEVENT in the source
+    pub index: usize,
+    /// Uid of the event, if it could be read
+    pub uid: Option<String>,
+    /// Name of the faulty property
+    pub property: String,
+    /// Raw value of the property
+    pub value: String,
+    /// Description of the error
+    pub error: String,
+}
+
+/// Report of the last parsing of a source.
+/// Malformed events are skipped individually and listed here, so the maintainer
+/// of the source can be told exactly what is broken.
+#[derive(Debug, Clone, Default)]
+pub struct ParseReport {
+    /// When the source was parsed
+    pub date: DateTime<Utc>,
+    /// Number of VEVENT read in the source
+    pub events: usize,
+    /// Number of recurring events expanded
+    pub recurring: usize,
+    /// Problems found in the source
+    pub issues: Vec<ParseIssue>,
+}
+
+/// Decodes a TEXT value (RFC 5545, section 3.3.11).
+/// The escaped characters are `\\`, `\;`, `\,` and the new lines (`\n` or `\N`).
+pub fn unescape_text(value: &str) -> String {
+    let mut text = String::with_capacity(value.len());
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            text.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n' | 'N') => text.push('\n'),
+            Some(escaped @ ('\\' | ';' | ',')) => text.push(escaped),
+            // invalid escapes are kept as-is
+            Some(other) => {
+                text.push('\\');
+                text.push(other);
+            }
+            None => text.push('\\'),
+        }
+    }
+
+    text
+}
+
+/// Applies the cleanup rules of a calendar to the text fields of an event
+fn clean_text(event: &mut Event, rules: &[CleanupRule]) {
+    for rule in rules {
+        for field in &rule.fields {
+            let text = match field {
+                TextField::Summary => &mut event.summary,
+                TextField::Description => &mut event.description,
+                TextField::Location => &mut event.location,
+            };
+
+            if let Cow::Owned(cleaned) = rule.pattern.replace_all(text, rule.replace.as_str()) {
+                *text = cleaned;
+            }
+        }
+    }
+
+    event.summary = event.summary.trim().to_string();
+    event.description = event.description.trim().to_string();
+    event.location = event.location.trim().to_string();
+}
+
+/// Decodes and cleans the text fields of an event stored before the TEXT values were decoded,
+/// so it matches the next fetch of the event.
+pub fn clean_stored_text(event: &mut Event, rules: &[CleanupRule]) {
+    event.summary = unescape_text(&event.summary);
+    event.description = unescape_text(&event.description);
+    event.location = unescape_text(&event.location);
+    clean_text(event, rules);
+}
+
+/// Builds an event and its recurrence from the properties of a VEVENT
+#[derive(Default)]
+struct EventBuilder<'a> {
+    event: Event,
+    recurrence: Recurrence<'a>,
+    transparent: bool,
+    end: Option<DateTime<Utc>>,
+    duration: Option<Duration>,
+}
+
+impl<'a> EventBuilder<'a> {
+    fn apply(&mut self, property: &'a Property, time: &TimeContext) -> Result<(), anyhow::Error> {
+        let Some(value) = &property.value else {
+            return Ok(());
+        };
+        let cal_event = &mut self.event;
+        let recurrence = &mut self.recurrence;
+
+        match &property.name as &str {
+            "DTSTART" => {
+                debug!("Parsing DTSTART: {}", value);
+                let start = time.parse(property)?;
+                cal_event.all_day = start.is_date();
+                cal_event.start = start.to_utc();
+                recurrence.dtstart = Some(property);
+            }
+            "DTEND" => {
+                debug!("Parsing DTEND: {}", value);
+                self.end = Some(time.parse(property)?.to_utc());
+            }
+            "DURATION" => {
+                self.duration = Some(parse_duration(value)?);
+            }
+            "SUMMARY" => {
+                cal_event.summary = unescape_text(value);
+            }
+            "LOCATION" => {
+                cal_event.location = unescape_text(value);
+            }
+            "DESCRIPTION" => {
+                cal_event.description = unescape_text(value);
+            }
+            "UID" => {
+                cal_event.uid = value.to_string();
+            }
+            "STATUS" => {
+                cal_event.status = match value.as_str() {
+                    "CANCELLED" => Status::Cancelled,
+                    "TENTATIVE" => Status::Tentative,
+                    _ => Status::Confirmed,
+                };
+            }
+            "TRANSP" => {
+                self.transparent = value == "TRANSPARENT";
+            }
+            "RRULE" => {
+                recurrence.rule = Some(value);
+            }
+            "RECURRENCE-ID" => {
+                recurrence.replaces = Some(time.parse(property)?.to_utc());
+            }
+            "EXDATE" => {
+                recurrence.exdates.extend(time.parse_list(property)?);
+            }
+            "RDATE" => {
+                recurrence.rdates.extend(
+                    time.parse_list(property)?
+                        .into_iter()
+                        .map(TimeValue::to_utc),
+                );
+            }
+            &_ => {}
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses a VEVENT into an event and the properties describing its recurrence.
+/// The error describes the first faulty property of the event, or the missing UID or DTSTART.
+fn parse_event<'a>(
+    index: usize,
+    event: &'a IcalEvent,
+    time: &TimeContext,
+    watch_item: &CalendarItem,
+) -> Result<(Event, Recurrence<'a>), ParseIssue> {
+    let mut builder = EventBuilder::default();
+    let issue = |property: &str, value: Option<&str>, error: String| ParseIssue {
+        index,
+        // the uid may be after the faulty property
+        uid: event
+            .properties
+            .iter()
+            .find(|property| property.name == "UID")
+            .and_then(|property| property.value.clone())
+            .filter(|uid| !uid.is_empty()),
+        property: property.to_string(),
+        value: value.unwrap_or_default().to_string(),
+        error,
+    };
+
+    for property in &event.properties {
+        builder.apply(property, time).map_err(|err| {
+            issue(
+                &property.name,
+                property.value.as_deref(),
+                format!("{err:#}"),
+            )
+        })?;
+    }
+
+    // an event without uid can't be followed between two fetches
+    if builder.event.uid.is_empty() {
+        return Err(issue("UID", None, "missing UID".to_string()));
+    }
+    if builder.recurrence.dtstart.is_none() {
+        return Err(issue("DTSTART", None, "missing DTSTART".to_string()));
+    }
+
+    let EventBuilder {
+        event: mut cal_event,
+        recurrence,
+        transparent,
+        end,
+        duration,
+    } = builder;
+
+    // without DTEND or DURATION, a date lasts for the whole day and a time is instantaneous
+    cal_event.end = match (end, duration) {
+        (Some(end), _) => end,
+        (None, Some(duration)) => cal_event
+            .start
+            .checked_add_signed(duration)
+            .unwrap_or(DateTime::<Utc>::MAX_UTC),
+        (None, None) if cal_event.all_day => cal_event.start + Duration::days(1),
+        (None, None) => cal_event.start,
+    };
+
+    clean_text(&mut cal_event, &watch_item.cleanup);
+
+    // some sources only mark the cancelled events as transparent
+    if watch_item.cancel_transparent && transparent {
+        cal_event.status = Status::Cancelled;
+    }
+
+    // an all-day event ending before its start lasts for a single day
+    if cal_event.all_day && cal_event.end <= cal_event.start {
+        cal_event.end = cal_event.start + Duration::days(1);
+    }
+
+    Ok((cal_event, recurrence))
+}
+
+/// Parses an iCalendar source into the list of events tracked by the bot.
+/// Recurring events are expanded in the range covered by the calendar (`time_amount`).
+/// Malformed events are skipped and described in the returned report.
+pub fn parse_calendar(
+    reader: impl BufRead,
+    watch_item: &CalendarItem,
+) -> Result<(Vec<Event>, ParseReport), anyhow::Error> {
+    let parser = ical::IcalParser::new(reader);
+    let mut events = Vec::new();
+
+    // recurring events are expanded in the range checked by the calendar
+    let from = Utc::now();
+    let to = from + watch_item.time_range()?;
+
+    let mut report = ParseReport {
+        date: from,
+        ..Default::default()
+    };
+
+    let timezone = watch_item.timezone.unwrap_or(Tz::UTC);
+
+    // modified occurrences of recurring events, applied once all the events are known
+    let mut overrides = Vec::new();
+
+    for calendar in parser {
+        let calendar = match calendar {
+            Ok(calendar) => calendar,
+            Err(err) => {
+                // the whole VCALENDAR block is unreadable
+                report.issues.push(ParseIssue {
+                    index: report.events,
+                    uid: None,
+                    property: "VCALENDAR".to_string(),
+                    value: String::new(),
+                    error: err.to_string(),
+                });
+                continue;
+            }
+        };
+        let time = TimeContext::new(timezone, &calendar.timezones);
+
+        for event in &calendar.events {
+            let index = report.events;
+            report.events += 1;
+
+            let (mut cal_event, recurrence) = match parse_event(index, event, &time, watch_item) {
+                Ok(parsed) => parsed,
+                Err(issue) => {
+                    warn!(
+                        "skipping the event #{} ({}): {}: {}",
+                        issue.index,
+                        issue.uid.as_deref().unwrap_or("unknown uid"),
+                        issue.property,
+                        issue.error
+                    );
+                    report.issues.push(issue);
+                    continue;
+                }
+            };
+
+            if let Some(recurrence_id) = recurrence.replaces {
+                cal_event.uid = occurrence_uid(&cal_event.uid, recurrence_id);
+                overrides.push(cal_event);
+            } else if recurrence.is_recurring() {
+                report.recurring += 1;
+
+                match recurrence.expand(&cal_event, &time, from, to) {
+                    Ok((occurrences, truncated)) => {
+                        if truncated {
+                            warn!("too many occurrences for the event {}", cal_event.uid);
+                            report.issues.push(ParseIssue {
+                                index,
+                                uid: Some(cal_event.uid.clone()),
+                                property: "RRULE".to_string(),
+                                value: recurrence.rule.unwrap_or_default().to_string(),
+                                error: "too many occurrences, the last ones are ignored"
+                                    .to_string(),
+                            });
+                        }
+                        events.extend(occurrences);
+                    }
+                    Err(err) => {
+                        // we keep the first occurrence, like a non-recurring event
+                        warn!(
+                            "failed to expand the recurring event {}: {}",
+                            cal_event.uid, err
+                        );
+                        report.issues.push(ParseIssue {
+                            index,
+                            uid: Some(cal_event.uid.clone()),
+                            property: "RRULE".to_string(),
+                            value: recurrence.rule.unwrap_or_default().to_string(),
+                            error: format!("only the first occurrence is kept: {err:#}"),
+                        });
+                        events.push(cal_event);
+                    }
+                }
+            } else {
+                events.push(cal_event);
+            }
+        }
+    }
+
+    apply_overrides(&mut events, overrides, from, to);
+    info!(
+        "Parsed {} events ({} issues)",
+        events.len(),
+        report.issues.len()
+    );
+
+    Ok((events, report))
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{DateTime, Duration, Utc};
+    use chrono_tz::Tz;
+    use regex::Regex;
+
+    use crate::{
+        calendar::{Event, Status},
+        cfg::{CalendarItem, CleanupRule, TextField},
+    };
+
+    use super::{clean_text, parse_calendar, unescape_text, ParseReport};
+
+    fn item() -> CalendarItem {
+        CalendarItem {
+            time_amount: "2w".to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Parses VEVENT blocks wrapped in a calendar
+    fn parse_with(events: &str, item: &CalendarItem) -> (Vec<Event>, ParseReport) {
+        let source = format!("BEGIN:VCALENDAR\nVERSION:2.0\n{events}END:VCALENDAR\n");
+        parse_calendar(source.as_bytes(), item).unwrap()
+    }
+
+    fn parse(events: &str) -> (Vec<Event>, ParseReport) {
+        parse_with(events, &item())
+    }
+
+    fn date(value: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(value).unwrap().to_utc()
+    }
+
+    #[test]
+    fn duration() {
+        let (events, _) = parse(
+            "BEGIN:VEVENT\nUID:1\nDTSTART:20261016T080000Z\nDURATION:PT1H30M\nEND:VEVENT\n\
+             BEGIN:VEVENT\nUID:2\nDTSTART;VALUE=DATE:20261016\nDURATION:P1W\nEND:VEVENT\n",
+        );
+
+        assert_eq!(events[0].end, date("2026-10-16T09:30:00Z"));
+        assert!(events[1].all_day);
+        assert_eq!(events[1].end - events[1].start, Duration::weeks(1));
+    }
+
+    #[test]
+    fn floating_time() {
+        let item = CalendarItem {
+            timezone: Some(Tz::Europe__Paris),
+            ..item()
+        };
+        let (events, _) = parse_with(
+            "BEGIN:VEVENT\nUID:1\nDTSTART:20261016T080000\nEND:VEVENT\n",
+            &item,
+        );
+
+        // the floating times use the timezone of the calendar
+        assert_eq!(events[0].start, date("2026-10-16T06:00:00Z"));
+    }
+
+    #[test]
+    fn missing_end() {
+        let (events, _) = parse(
+            "BEGIN:VEVENT\nUID:1\nDTSTART:20261016T080000Z\nEND:VEVENT\n\
+             BEGIN:VEVENT\nUID:2\nDTSTART;VALUE=DATE:20261016\nEND:VEVENT\n",
+        );
+
+        // a time is instantaneous and a date lasts for the whole day
+        assert_eq!(events[0].end, events[0].start);
+        assert_eq!(events[1].end, date("2026-10-17T00:00:00Z"));
+    }
+
+    #[test]
+    fn recurring_without_end() {
+        let start = Utc::now() + Duration::days(1);
+        let (events, report) = parse(&format!(
+            "BEGIN:VEVENT\nUID:1\nDTSTART:{}\nDURATION:PT1H\nRRULE:FREQ=DAILY;COUNT=3\nEND:VEVENT\n",
+            start.format("%Y%m%dT%H0000Z")
+        ));
+
+        assert!(report.issues.is_empty());
+        assert_eq!(events.len(), 3);
+        assert!(events
+            .iter()
+            .all(|event| event.end - event.start == Duration::hours(1)));
+    }
+
+    #[test]
+    fn old_recurring() {
+        // a series started long ago is still expanded in the range
+        let (events, report) =
+            parse("BEGIN:VEVENT\nUID:1\nDTSTART:19900101T080000Z\nRRULE:FREQ=DAILY\nEND:VEVENT\n");
+
+        assert!(report.issues.is_empty());
+        assert_eq!(events.len(), 14);
+        assert!(events.iter().all(|event| event.start > Utc::now()));
+
+        // a count needs every occurrence since the start, so the rule is cut and reported
+        let (events, report) = parse(
+            "BEGIN:VEVENT\nUID:1\nDTSTART:19900101T080000Z\nRRULE:FREQ=DAILY;COUNT=20000\nEND:VEVENT\n",
+        );
+
+        assert!(events.is_empty());
+        assert_eq!(report.issues.len(), 1);
+        assert_eq!(report.issues[0].property, "RRULE");
+    }
+
+    #[test]
+    fn missing_properties() {
+        let (events, report) = parse(
+            "BEGIN:VEVENT\nSUMMARY:No uid\nDTSTART:20261016T080000Z\nEND:VEVENT\n\
+             BEGIN:VEVENT\nUID:2\nSUMMARY:No start\nEND:VEVENT\n\
+             BEGIN:VEVENT\nUID:3\nDTSTART:20261016T080000Z\nEND:VEVENT\n",
+        );
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].uid, "3");
+
+        let issues: Vec<(usize, Option<&str>, &str)> = report
+            .issues
+            .iter()
+            .map(|issue| (issue.index, issue.uid.as_deref(), issue.property.as_str()))
+            .collect();
+        assert_eq!(issues, vec![(0, None, "UID"), (1, Some("2"), "DTSTART")]);
+    }
+
+    #[test]
+    fn status() {
+        let events =
+            "BEGIN:VEVENT\nUID:1\nDTSTART:20261016T080000Z\nSTATUS:CANCELLED\nEND:VEVENT\n\
+             BEGIN:VEVENT\nUID:2\nDTSTART:20261016T080000Z\nSTATUS:TENTATIVE\nEND:VEVENT\n\
+             BEGIN:VEVENT\nUID:3\nDTSTART:20261016T080000Z\nSTATUS:CONFIRMED\nEND:VEVENT\n\
+             BEGIN:VEVENT\nUID:4\nDTSTART:20261016T080000Z\nTRANSP:TRANSPARENT\nEND:VEVENT\n";
+
+        let statuses = |events: Vec<Event>| -> Vec<Status> {
+            events.iter().map(|event| event.status).collect()
+        };
+
+        assert_eq!(
+            statuses(parse(events).0),
+            vec![
+                Status::Cancelled,
+                Status::Tentative,
+                Status::Confirmed,
+                Status::Confirmed
+            ]
+        );
+
+        // some sources only mark the cancelled events as transparent
+        let item = CalendarItem {
+            cancel_transparent: true,
+            ..item()
+        };
+        assert_eq!(statuses(parse_with(events, &item).0)[3], Status::Cancelled);
+    }
+
+    #[test]
+    fn parse_issues() {
+        let start = Utc::now() + Duration::days(1);
+        let (events, report) = parse(&format!(
+            "BEGIN:VEVENT\nDTSTART:2026-10-16\nUID:1\nEND:VEVENT\n\
+             BEGIN:VEVENT\nUID:2\nDTSTART:{0}\nRRULE:FREQ=SECONDLY\nEND:VEVENT\n\
+             BEGIN:VEVENT\nUID:3\nDTSTART:{0}\nRRULE:FREQ=DAILY;COUNT=2\nEND:VEVENT\n",
+            start.format("%Y%m%dT%H0000Z")
+        ));
+
+        assert_eq!(report.events, 3);
+        assert_eq!(report.recurring, 2);
+        assert_eq!(report.issues.len(), 2);
+
+        // the faulty event is skipped, the uid is read after the faulty property
+        let issue = &report.issues[0];
+        assert_eq!(issue.index, 0);
+        assert_eq!(issue.uid.as_deref(), Some("1"));
+        assert_eq!(issue.property, "DTSTART");
+        assert_eq!(issue.value, "2026-10-16");
+
+        // only the first occurrence of an unsupported rule is kept
+        let issue = &report.issues[1];
+        assert_eq!(issue.index, 1);
+        assert_eq!(issue.property, "RRULE");
+        assert_eq!(issue.value, "FREQ=SECONDLY");
+
+        let uids: Vec<&str> = events.iter().map(|event| event.uid.as_str()).collect();
+        assert_eq!(uids.len(), 3);
+        assert_eq!(uids[0], "2");
+        assert!(uids[1..].iter().all(|uid| uid.starts_with("3/")));
+    }
+
+    #[test]
+    fn unescape() {
+        assert_eq!(
+            unescape_text(r"Maths\, TD\; groupe A\\B\nSalle\NA001"),
+            "Maths, TD; groupe A\\B\nSalle\nA001"
+        );
+        // invalid escapes are kept
+        assert_eq!(unescape_text(r"C:\temp\"), r"C:\temp\");
+
+        let (events, _) = parse(
+            "BEGIN:VEVENT\nUID:1\nDTSTART:20261016T080000Z\n\
+             SUMMARY:Maths\\, TD\nLOCATION:A001\\; A002\nDESCRIPTION:Groupe A\\nGroupe B\n\
+             END:VEVENT\n",
+        );
+        assert_eq!(events[0].summary, "Maths, TD");
+        assert_eq!(events[0].location, "A001; A002");
+        assert_eq!(events[0].description, "Groupe A\nGroupe B");
+    }
+
+    #[test]
+    fn cleanup_rules() {
+        let rules = vec![
+            CleanupRule {
+                pattern: Regex::new(r"\(.*\)").unwrap(),
+                replace: String::new(),
+                fields: vec![TextField::Description],
+            },
+            CleanupRule {
+                pattern: Regex::new(r"^CM (.*)$").unwrap(),
+                replace: "Cours magistral : $1".to_string(),
+                fields: vec![TextField::Summary, TextField::Location],
+            },
+        ];
+        let mut event = Event {
+            summary: "CM Maths".to_string(),
+            description: "Groupe A\n(Exporté le 16/10/2026)".to_string(),
+            location: " A001 ".to_string(),
+            ..Default::default()
+        };
+
+        clean_text(&mut event, &rules);
+
+        assert_eq!(event.summary, "Cours magistral : Maths");
+        assert_eq!(event.description, "Groupe A");
+        // the fields are trimmed even when no rule applies
+        assert_eq!(event.location, "A001");
+
+        // the calendars without rules remove the export date added by ADE
+        let (events, _) = parse(
+            "BEGIN:VEVENT\nUID:1\nDTSTART:20261016T080000Z\n\
+             SUMMARY:Maths (TD)\nDESCRIPTION:Groupe A (TP)\\n(Exporté le 16/10/2026)\n\
+             END:VEVENT\n",
+        );
+        assert_eq!(events[0].summary, "Maths (TD)");
+        assert_eq!(events[0].description, "Groupe A (TP)");
+    }
+}
@@ -0,0 +1,827 @@
+use std::{collections::HashMap, str::FromStr};
+
+use anyhow::{bail, Context};
+use chrono::{DateTime, Datelike, Duration, Months, NaiveDate, NaiveDateTime, Utc, Weekday};
+use ical::property::Property;
+
+use super::{
+    time::{nth_weekday, parse_local, parse_weekday, split_weekday, TimeContext, TimeValue},
+    Event,
+};
+
+/// Format of the start of an occurrence in its uid
+const OCCURRENCE_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// Maximum number of periods computed for a single rule.
+/// The periods before the expanded range are skipped when the rule has no `COUNT`,
+/// so this only limits the rules with a count and the very long ranges.
+const MAX_PERIODS: u32 = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+/// End of a recurrence rule (`UNTIL`), in the form used by the rule.
+#[derive(Debug, Clone, Copy)]
+enum Until {
+    Utc(DateTime<Utc>),
+    Local(NaiveDateTime),
+}
+
+/// A recurrence rule (`RRULE`).
+/// Only the parts used by school calendars are supported, i.e. the daily, weekly,
+/// monthly and yearly frequencies with the `BYDAY`, `BYMONTHDAY` and `BYMONTH` filters.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    frequency: Frequency,
+    interval: u32,
+    count: Option<u32>,
+    until: Option<Until>,
+    by_day: Vec<(Option<i8>, Weekday)>,
+    by_month_day: Vec<i8>,
+    by_month: Vec<u32>,
+}
+
+impl FromStr for Rule {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut frequency = None;
+        let mut rule = Self {
+            frequency: Frequency::Daily,
+            interval: 1,
+            count: None,
+            until: None,
+            by_day: vec![],
+            by_month_day: vec![],
+            by_month: vec![],
+        };
+
+        for part in value.split(';').filter(|part| !part.is_empty()) {
+            let (name, value) = part
+                .split_once('=')
+                .with_context(|| format!("invalid rule part: {part}"))?;
+
+            match name {
+                "FREQ" => {
+                    frequency = Some(match value {
+                        "DAILY" => Frequency::Daily,
+                        "WEEKLY" => Frequency::Weekly,
+                        "MONTHLY" => Frequency::Monthly,
+                        "YEARLY" => Frequency::Yearly,
+                        _ => bail!("unsupported frequency: {value}"),
+                    });
+                }
+                "INTERVAL" => rule.interval = value.parse::<u32>()?.max(1),
+                "COUNT" => rule.count = Some(value.parse()?),
+                "UNTIL" => {
+                    let local = parse_local(value)?;
+                    rule.until = Some(if value.ends_with('Z') {
+                        Until::Utc(local.and_utc())
+                    } else if value.contains('T') {
+                        Until::Local(local)
+                    } else {
+                        // a date includes the whole day
+                        Until::Local(local + Duration::days(1) - Duration::seconds(1))
+                    });
+                }
+                "BYDAY" => {
+                    for day in value.split(',') {
+                        let (n, weekday) = split_weekday(day);
+                        let n = if n.is_empty() {
+                            None
+                        } else {
+                            Some(n.trim_start_matches('+').parse()?)
+                        };
+                        rule.by_day.push((n, parse_weekday(weekday)?));
+                    }
+                }
+                "BYMONTHDAY" => {
+                    for day in value.split(',') {
+                        rule.by_month_day.push(day.parse()?);
+                    }
+                }
+                "BYMONTH" => {
+                    for month in value.split(',') {
+                        rule.by_month.push(month.parse()?);
+                    }
+                }
+                // the week start only matters for the rules we don't support
+                "WKST" => {}
+                _ => bail!("unsupported rule part: {name}"),
+            }
+        }
+
+        rule.frequency = frequency.context("missing FREQ in the rule")?;
+        Ok(rule)
+    }
+}
+
+/// Returns the day of a month, negative values count from the end of the month.
+fn month_day(year: i32, month: u32, day: i8) -> Option<NaiveDate> {
+    if day > 0 {
+        NaiveDate::from_ymd_opt(year, month, u32::from(day.unsigned_abs()))
+    } else {
+        let first = NaiveDate::from_ymd_opt(year, month, 1)?;
+        let last = first.checked_add_months(Months::new(1))?.pred_opt()?;
+        let date = last - Duration::days(i64::from(day.unsigned_abs()) - 1);
+
+        (date.month() == month).then_some(date)
+    }
+}
+
+impl Rule {
+    /// Days of a month matching the rule
+    fn month_days(&self, year: i32, month: u32, start: NaiveDate) -> Vec<NaiveDate> {
+        let mut days: Vec<NaiveDate> = if !self.by_month_day.is_empty() {
+            self.by_month_day
+                .iter()
+                .filter_map(|day| month_day(year, month, *day))
+                .collect()
+        } else if !self.by_day.is_empty() {
+            self.by_day
+                .iter()
+                .flat_map(|(n, weekday)| {
+                    n.map_or_else(
+                        // every weekday of the month
+                        || {
+                            (1..=5)
+                                .filter_map(|n| nth_weekday(year, month, n, *weekday))
+                                .collect::<Vec<_>>()
+                        },
+                        |n| nth_weekday(year, month, n, *weekday).into_iter().collect(),
+                    )
+                })
+                .collect()
+        } else {
+            NaiveDate::from_ymd_opt(year, month, start.day())
+                .into_iter()
+                .collect()
+        };
+
+        // when both are given, BYDAY filters the days of BYMONTHDAY
+        if !self.by_month_day.is_empty() && !self.by_day.is_empty() {
+            days.retain(|date| self.by_day.iter().any(|(_, day)| *day == date.weekday()));
+        }
+
+        days
+    }
+
+    /// Candidates dates for the n-th period of the rule.
+    /// Fails when the period is beyond the dates supported by the bot.
+    fn period(&self, start: NaiveDate, index: u32) -> Result<Vec<NaiveDate>, anyhow::Error> {
+        let step = index
+            .checked_mul(self.interval)
+            .context("the rule exceeds the supported dates")?;
+        let shift = |date: NaiveDate, duration: Option<Duration>| {
+            duration
+                .and_then(|duration| date.checked_add_signed(duration))
+                .context("the rule exceeds the supported dates")
+        };
+
+        let mut days = match self.frequency {
+            Frequency::Daily => {
+                let day = shift(start, Duration::try_days(i64::from(step)))?;
+                if self.by_day.is_empty() || self.by_day.iter().any(|(_, d)| *d == day.weekday()) {
+                    vec![day]
+                } else {
+                    vec![]
+                }
+            }
+            Frequency::Weekly => {
+                let week = shift(
+                    start.week(Weekday::Mon).first_day(),
+                    Duration::try_weeks(i64::from(step)),
+                )?;
+                if self.by_day.is_empty() {
+                    vec![shift(
+                        week,
+                        Duration::try_days(i64::from(start.weekday().num_days_from_monday())),
+                    )?]
+                } else {
+                    self.by_day
+                        .iter()
+                        .map(|(_, day)| {
+                            shift(
+                                week,
+                                Duration::try_days(i64::from(day.num_days_from_monday())),
+                            )
+                        })
+                        .collect::<Result<_, _>>()?
+                }
+            }
+            Frequency::Monthly => {
+                let month = start
+                    .with_day(1)
+                    .and_then(|first| first.checked_add_months(Months::new(step)))
+                    .context("the rule exceeds the supported dates")?;
+                self.month_days(month.year(), month.month(), start)
+            }
+            Frequency::Yearly => {
+                let year = i32::try_from(step)
+                    .ok()
+                    .and_then(|step| start.year().checked_add(step))
+                    .filter(|year| NaiveDate::from_yo_opt(*year, 1).is_some())
+                    .context("the rule exceeds the supported dates")?;
+                let months = if self.by_month.is_empty() {
+                    vec![start.month()]
+                } else {
+                    self.by_month.clone()
+                };
+                months
+                    .into_iter()
+                    .flat_map(|month| self.month_days(year, month, start))
+                    .collect()
+            }
+        };
+
+        if !self.by_month.is_empty() {
+            days.retain(|day| self.by_month.contains(&day.month()));
+        }
+        days.sort_unstable();
+        days.dedup();
+        Ok(days)
+    }
+
+    /// Index of the period containing `date`, 0 for the dates before the start.
+    fn period_of(&self, start: NaiveDate, date: NaiveDate) -> u32 {
+        let elapsed = match self.frequency {
+            Frequency::Daily => (date - start).num_days(),
+            Frequency::Weekly => (date.week(Weekday::Mon).first_day()
+                - start.week(Weekday::Mon).first_day())
+            .num_weeks(),
+            Frequency::Monthly => {
+                i64::from(date.year() - start.year()) * 12 + i64::from(date.month())
+                    - i64::from(start.month())
+            }
+            Frequency::Yearly => i64::from(date.year() - start.year()),
+        };
+
+        u32::try_from(elapsed.max(0) / i64::from(self.interval)).unwrap_or(u32::MAX)
+    }
+
+    /// Computes the starts of the occurrences of the rule after `from`, until `limit` is reached.
+    /// `to_utc` converts a local time of the series to utc.
+    /// The returned flag tells if the rule was cut after [`MAX_PERIODS`] periods.
+    pub fn occurrences(
+        &self,
+        start: NaiveDateTime,
+        from: DateTime<Utc>,
+        limit: DateTime<Utc>,
+        to_utc: impl Fn(NaiveDateTime) -> Result<DateTime<Utc>, anyhow::Error>,
+    ) -> Result<(Vec<DateTime<Utc>>, bool), anyhow::Error> {
+        // the first occurrence is always the start of the event
+        let mut occurrences = vec![to_utc(start)?];
+
+        // a count needs every occurrence since the start, otherwise the periods before `from`
+        // are skipped (with a day of margin, for the offset between local time and utc)
+        let first = if self.count.is_some() {
+            0
+        } else {
+            let from = from.naive_utc().date().pred_opt().unwrap_or(NaiveDate::MIN);
+            self.period_of(start.date(), from)
+        };
+
+        for index in first..first.saturating_add(MAX_PERIODS) {
+            for day in self.period(start.date(), index)? {
+                let local = day.and_time(start.time());
+                if local <= start {
+                    continue;
+                }
+
+                let date = to_utc(local)?;
+                let ended = match self.until {
+                    Some(Until::Utc(until)) => date > until,
+                    Some(Until::Local(until)) => local > until,
+                    None => false,
+                };
+
+                if ended
+                    || date > limit
+                    || self
+                        .count
+                        .is_some_and(|count| occurrences.len() >= count as usize)
+                {
+                    return Ok((occurrences, false));
+                }
+
+                occurrences.push(date);
+            }
+        }
+
+        Ok((occurrences, true))
+    }
+}
+
+/// Unique id of an occurrence of a recurring event.
+/// The occurrences share the uid of the series, so the start is used to tell them apart.
+pub fn occurrence_uid(uid: &str, start: DateTime<Utc>) -> String {
+    format!("{uid}/{}", start.format(OCCURRENCE_FORMAT))
+}
+
+/// Returns the uid of the series of an occurrence, `None` if the event isn't an occurrence
+pub fn series_uid(uid: &str) -> Option<&str> {
+    let (series, start) = uid.rsplit_once('/')?;
+
+    NaiveDateTime::parse_from_str(start, OCCURRENCE_FORMAT)
+        .is_ok()
+        .then_some(series)
+}
+
+/// The recurrence properties of an event
+#[derive(Debug, Default)]
+pub struct Recurrence<'a> {
+    pub dtstart: Option<&'a Property>,
+    pub rule: Option<&'a str>,
+    /// Excluded occurrences, a date excludes the occurrence of the series on this day
+    pub exdates: Vec<TimeValue>,
+    pub rdates: Vec<DateTime<Utc>>,
+    /// Start of the occurrence replaced by this event (`RECURRENCE-ID`)
+    pub replaces: Option<DateTime<Utc>>,
+}
+
+impl Recurrence<'_> {
+    pub const fn is_recurring(&self) -> bool {
+        self.rule.is_some() || !self.rdates.is_empty()
+    }
+
+    /// Expands a recurring event into the occurrences overlapping `[from, to]`.
+    /// Each occurrence is a copy of the event with its own start and uid.
+    /// The returned flag tells if the occurrences were cut before the end of the range.
+    pub fn expand(
+        &self,
+        event: &Event,
+        time: &TimeContext,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<(Vec<Event>, bool), anyhow::Error> {
+        let dtstart = self.dtstart.context("recurring event without DTSTART")?;
+        let duration = event.end - event.start;
+        let start = parse_local(dtstart.value.as_deref().unwrap_or_default())?;
+
+        let (mut starts, truncated) = match self.rule {
+            Some(rule) => {
+                let rule: Rule = rule.parse()?;
+                // the occurrences starting before `from` may still be running
+                let earliest = from
+                    .checked_sub_signed(duration)
+                    .unwrap_or(DateTime::<Utc>::MIN_UTC);
+
+                rule.occurrences(start, earliest, to, |local| {
+                    time.at(dtstart, local).map(TimeValue::to_utc)
+                })?
+            }
+            None => (vec![event.start], false),
+        };
+
+        // the occurrences of the series have the wall clock time of its start
+        let exdates = self
+            .exdates
+            .iter()
+            .map(|exdate| match exdate {
+                TimeValue::Date(date) => time
+                    .at(dtstart, date.and_time(start.time()))
+                    .map(TimeValue::to_utc),
+                TimeValue::DateTime(date) => Ok(*date),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        starts.extend(&self.rdates);
+        starts.retain(|start| !exdates.contains(start));
+        starts.sort_unstable();
+        starts.dedup();
+
+        let events = starts
+            .into_iter()
+            .filter(|start| *start + duration > from && *start <= to)
+            .map(|start| Event {
+                start,
+                end: start + duration,
+                uid: occurrence_uid(&event.uid, start),
+                ..event.clone()
+            })
+            .collect();
+
+        Ok((events, truncated))
+    }
+}
+
+/// Replaces the expanded occurrences by their overrides (the events with a `RECURRENCE-ID`).
+/// Overrides and occurrences share the same synthetic uid, so the rest of the series is untouched
+/// and a moved occurrence is seen as an update of this occurrence only.
+/// Like the occurrences, only the overrides overlapping `[from, to]` are kept.
+pub fn apply_overrides(
+    events: &mut Vec<Event>,
+    overrides: Vec<Event>,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) {
+    let index: HashMap<String, usize> = events
+        .iter()
+        .enumerate()
+        .map(|(position, event)| (event.uid.clone(), position))
+        .collect();
+    // occurrences moved outside of the range
+    let mut moved_out = Vec::new();
+
+    for event in overrides {
+        let visible = event.end > from && event.start <= to;
+
+        match index.get(&event.uid) {
+            Some(position) if visible => events[*position] = event,
+            Some(position) => moved_out.push(*position),
+            // the occurrence is outside of the expanded range or the series isn't in the source
+            None if visible => events.push(event),
+            None => {}
+        }
+    }
+
+    // the overrides are pushed after the occurrences, so the positions are still valid
+    moved_out.sort_unstable();
+    moved_out.dedup();
+    for position in moved_out.into_iter().rev() {
+        events.remove(position);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, Utc};
+    use chrono_tz::Tz;
+    use ical::property::Property;
+
+    use crate::calendar::{
+        time::{TimeContext, TimeValue},
+        Event,
+    };
+
+    use super::{apply_overrides, occurrence_uid, series_uid, Recurrence, Rule, MAX_PERIODS};
+
+    fn local(value: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").unwrap()
+    }
+
+    fn utc(value: &str) -> DateTime<Utc> {
+        local(value).and_utc()
+    }
+
+    /// Computes the occurrences of a rule in utc, from its start until `limit`
+    fn starts(rule: &str, start: &str, limit: &str) -> Vec<DateTime<Utc>> {
+        rule.parse::<Rule>()
+            .unwrap()
+            .occurrences(local(start), utc(start), utc(limit), |local| {
+                Ok(local.and_utc())
+            })
+            .unwrap()
+            .0
+    }
+
+    fn dates(values: &[&str]) -> Vec<DateTime<Utc>> {
+        values.iter().map(|value| utc(value)).collect()
+    }
+
+    fn dtstart(value: &str, tzid: Option<&str>) -> Property {
+        Property {
+            name: "DTSTART".to_string(),
+            params: tzid.map(|tzid| vec![("TZID".to_string(), vec![tzid.to_string()])]),
+            value: Some(value.to_string()),
+        }
+    }
+
+    fn event(uid: &str, start: DateTime<Utc>) -> Event {
+        Event {
+            uid: uid.to_string(),
+            summary: "Maths".to_string(),
+            start,
+            end: start + Duration::hours(1),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn frequencies() {
+        assert_eq!(
+            starts("FREQ=DAILY;COUNT=3", "20261016T080000", "20270101T000000"),
+            dates(&["20261016T080000", "20261017T080000", "20261018T080000"])
+        );
+        // every other week, on mondays and wednesdays
+        assert_eq!(
+            starts(
+                "FREQ=WEEKLY;INTERVAL=2;BYDAY=MO,WE;COUNT=5",
+                "20261012T080000",
+                "20270101T000000"
+            ),
+            dates(&[
+                "20261012T080000",
+                "20261014T080000",
+                "20261026T080000",
+                "20261028T080000",
+                "20261109T080000"
+            ])
+        );
+        // the last friday of the month
+        assert_eq!(
+            starts(
+                "FREQ=MONTHLY;BYDAY=-1FR;COUNT=3",
+                "20261030T100000",
+                "20270101T000000"
+            ),
+            dates(&["20261030T100000", "20261127T100000", "20261225T100000"])
+        );
+        // the months without a 31st are skipped
+        assert_eq!(
+            starts(
+                "FREQ=MONTHLY;BYMONTHDAY=31;COUNT=3",
+                "20261031T100000",
+                "20270601T000000"
+            ),
+            dates(&["20261031T100000", "20261231T100000", "20270131T100000"])
+        );
+        assert_eq!(
+            starts(
+                "FREQ=YEARLY;BYMONTH=1,6;COUNT=3",
+                "20260115T100000",
+                "20300101T000000"
+            ),
+            dates(&["20260115T100000", "20260615T100000", "20270115T100000"])
+        );
+    }
+
+    #[test]
+    fn end_of_rule() {
+        let expected = dates(&["20261016T080000", "20261017T080000", "20261018T080000"]);
+
+        // the end of the rule is inclusive
+        assert_eq!(
+            starts(
+                "FREQ=DAILY;UNTIL=20261018T080000Z",
+                "20261016T080000",
+                "20270101T000000"
+            ),
+            expected
+        );
+        assert_eq!(
+            starts(
+                "FREQ=DAILY;UNTIL=20261018",
+                "20261016T080000",
+                "20270101T000000"
+            ),
+            expected
+        );
+        // the occurrences are only computed until the limit
+        assert_eq!(
+            starts("FREQ=DAILY", "20261016T080000", "20261018T090000"),
+            expected
+        );
+    }
+
+    #[test]
+    fn max_periods() {
+        let (occurrences, truncated) = "FREQ=DAILY"
+            .parse::<Rule>()
+            .unwrap()
+            .occurrences(
+                local("20000101T080000"),
+                utc("20000101T080000"),
+                utc("21000101T000000"),
+                |local| Ok(local.and_utc()),
+            )
+            .unwrap();
+
+        assert!(truncated);
+        assert_eq!(occurrences.len(), MAX_PERIODS as usize);
+        assert!(occurrences.last().unwrap() < &utc("21000101T000000"));
+    }
+
+    #[test]
+    fn old_rules() {
+        // the periods before `from` are skipped
+        for (rule, expected) in [
+            ("FREQ=DAILY", vec!["20261016T080000", "20261017T080000"]),
+            ("FREQ=WEEKLY;BYDAY=MO,FR", vec!["20261016T080000"]),
+            ("FREQ=MONTHLY;BYMONTHDAY=16", vec!["20261016T080000"]),
+            (
+                "FREQ=YEARLY;INTERVAL=2;BYMONTH=10;BYMONTHDAY=16",
+                vec!["20261016T080000"],
+            ),
+        ] {
+            let (occurrences, truncated) = rule
+                .parse::<Rule>()
+                .unwrap()
+                .occurrences(
+                    local("19900101T080000"),
+                    utc("20261016T000000"),
+                    utc("20261017T120000"),
+                    |local| Ok(local.and_utc()),
+                )
+                .unwrap();
+
+            assert!(!truncated, "{rule}");
+            assert_eq!(occurrences[0], utc("19900101T080000"), "{rule}");
+            let last: Vec<DateTime<Utc>> = occurrences
+                .into_iter()
+                .filter(|start| *start >= utc("20261016T000000"))
+                .collect();
+            assert_eq!(last, dates(&expected), "{rule}");
+        }
+    }
+
+    #[test]
+    fn overflow() {
+        for rule in [
+            "FREQ=DAILY;INTERVAL=4000000000",
+            "FREQ=WEEKLY;INTERVAL=2000000000",
+            "FREQ=MONTHLY;INTERVAL=4000000000",
+            "FREQ=YEARLY;INTERVAL=4000000000",
+        ] {
+            let occurrences = rule.parse::<Rule>().unwrap().occurrences(
+                local("20261016T080000"),
+                utc("20261016T080000"),
+                utc("21000101T000000"),
+                |local| Ok(local.and_utc()),
+            );
+
+            assert!(occurrences.is_err(), "{rule}");
+        }
+    }
+
+    #[test]
+    fn invalid_rules() {
+        for rule in [
+            "FREQ=SECONDLY",
+            "INTERVAL=2",
+            "FREQ=DAILY;BYSETPOS=1",
+            "FREQ=DAILY;COUNT=x",
+            "FREQ=WEEKLY;BYDAY=XX",
+            "FREQ=WEEKLY;BYDAY=éx",
+        ] {
+            assert!(rule.parse::<Rule>().is_err(), "{rule}");
+        }
+    }
+
+    #[test]
+    fn exceptions() {
+        let dtstart = dtstart("20261016T080000Z", None);
+        let recurrence = Recurrence {
+            dtstart: Some(&dtstart),
+            rule: Some("FREQ=DAILY;COUNT=5"),
+            exdates: vec![TimeValue::DateTime(utc("20261017T080000"))],
+            rdates: dates(&["20261025T100000"]),
+            replaces: None,
+        };
+        let time = TimeContext::new(Tz::UTC, &[]);
+
+        let events = recurrence
+            .expand(
+                &event("1", utc("20261016T080000")),
+                &time,
+                utc("20261017T000000"),
+                utc("20261101T000000"),
+            )
+            .unwrap()
+            .0;
+
+        // the first occurrence ended before the range
+        assert_eq!(
+            events.iter().map(|event| event.start).collect::<Vec<_>>(),
+            dates(&[
+                "20261018T080000",
+                "20261019T080000",
+                "20261020T080000",
+                "20261025T100000"
+            ])
+        );
+        assert_eq!(events[0].uid, "1/20261018T080000Z");
+        assert!(events
+            .iter()
+            .all(|event| event.end - event.start == Duration::hours(1)));
+    }
+
+    #[test]
+    fn date_exceptions() {
+        // a date excludes the occurrence of the day, whatever its time
+        let dtstart = dtstart("20261019T080000", Some("Europe/Paris"));
+        let recurrence = Recurrence {
+            dtstart: Some(&dtstart),
+            rule: Some("FREQ=WEEKLY;COUNT=3"),
+            exdates: vec![TimeValue::Date(
+                NaiveDate::from_ymd_opt(2026, 10, 26).unwrap(),
+            )],
+            ..Default::default()
+        };
+        let time = TimeContext::new(Tz::UTC, &[]);
+
+        let events = recurrence
+            .expand(
+                &event("1", utc("20261019T060000")),
+                &time,
+                utc("20261001T000000"),
+                utc("20261201T000000"),
+            )
+            .unwrap()
+            .0;
+
+        assert_eq!(
+            events.iter().map(|event| event.start).collect::<Vec<_>>(),
+            dates(&["20261019T060000", "20261102T070000"])
+        );
+    }
+
+    #[test]
+    fn local_recurrence() {
+        // the occurrences keep their wall clock time when the clocks go back
+        let dtstart = dtstart("20261019T080000", Some("Europe/Paris"));
+        let recurrence = Recurrence {
+            dtstart: Some(&dtstart),
+            rule: Some("FREQ=WEEKLY;COUNT=2"),
+            ..Default::default()
+        };
+        let time = TimeContext::new(Tz::UTC, &[]);
+
+        let events = recurrence
+            .expand(
+                &event("1", utc("20261019T060000")),
+                &time,
+                utc("20261001T000000"),
+                utc("20261101T000000"),
+            )
+            .unwrap()
+            .0;
+
+        assert_eq!(
+            events.iter().map(|event| event.start).collect::<Vec<_>>(),
+            dates(&["20261019T060000", "20261026T070000"])
+        );
+    }
+
+    #[test]
+    fn overrides() {
+        let mut events: Vec<Event> = dates(&["20261016T080000", "20261017T080000"])
+            .into_iter()
+            .map(|start| event(&occurrence_uid("1", start), start))
+            .collect();
+
+        // the second occurrence is moved in the afternoon
+        let moved = event(
+            &occurrence_uid("1", utc("20261017T080000")),
+            utc("20261017T140000"),
+        );
+        // the occurrence of a series missing from the source
+        let orphan = event(
+            &occurrence_uid("2", utc("20261017T080000")),
+            utc("20261017T080000"),
+        );
+        apply_overrides(
+            &mut events,
+            vec![moved.clone(), orphan.clone()],
+            utc("20261016T000000"),
+            utc("20261101T000000"),
+        );
+
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[1], moved);
+        assert_eq!(events[2], orphan);
+    }
+
+    #[test]
+    fn overrides_window() {
+        let mut events: Vec<Event> = dates(&["20261016T080000", "20261017T080000"])
+            .into_iter()
+            .map(|start| event(&occurrence_uid("1", start), start))
+            .collect();
+        let first = events[0].clone();
+
+        // the second occurrence is moved after the range
+        let moved = event(
+            &occurrence_uid("1", utc("20261017T080000")),
+            utc("20261215T080000"),
+        );
+        // an occurrence which is not expanded because it's before the range
+        let past = event(
+            &occurrence_uid("1", utc("20261001T080000")),
+            utc("20261002T080000"),
+        );
+        apply_overrides(
+            &mut events,
+            vec![moved, past],
+            utc("20261016T000000"),
+            utc("20261101T000000"),
+        );
+
+        assert_eq!(events, vec![first]);
+    }
+
+    #[test]
+    fn series() {
+        let uid = occurrence_uid("abc/def", utc("20261016T080000"));
+
+        assert_eq!(uid, "abc/def/20261016T080000Z");
+        assert_eq!(series_uid(&uid), Some("abc/def"));
+        assert_eq!(series_uid("abc/def"), None);
+        assert_eq!(series_uid("abc"), None);
+    }
+}
@@ -1,25 +1,37 @@
 use std::{
     collections::{BTreeMap, HashMap},
-    fs, io,
+    fs,
     ops::Add,
     sync::Arc,
 };
 
-use anyhow::{bail, Context};
+use anyhow::Context;
 use chrono::{DateTime, Duration, Utc};
-use log::{debug, info};
+use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
 
 use crate::cfg::{CalendarItem, Config};
 
-use super::{Event, UpdateResult};
+use super::{
+    database,
+    history::History,
+    recurrence::series_uid,
+    settle::{self, PendingChanges},
+    source::SourceState,
+    Event, UpdateResult,
+};
+
+/// Key of the time index of a calendar.
+/// Many events can start at the same instant (parallel groups, exams in many rooms),
+/// so the uid is used to tell them apart.
+type TimeKey = (DateTime<Utc>, String);
 
 /// A calendar is a collection of events
 /// and utility functions used to search and sort them.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Calendar {
     // used to easily compute using dates
-    tree: BTreeMap<DateTime<Utc>, Arc<Event>>,
+    tree: BTreeMap<TimeKey, Arc<Event>>,
     // used to search based on uids
     uid_index: HashMap<String, Arc<Event>>,
 }
@@ -30,15 +42,25 @@ impl<'de> Deserialize<'de> for Calendar {
         D: serde::Deserializer<'de>,
     {
         let elements: Vec<Arc<Event>> = Vec::deserialize(deserializer)?;
-        let mut tree = BTreeMap::new();
-        let mut uid_index = HashMap::new();
+        let mut calendar = Self::new();
 
         for item in elements {
-            tree.insert(item.start, item.clone());
-            uid_index.insert(item.uid.clone(), item);
+            calendar.insert(item);
+        }
+
+        Ok(calendar)
+    }
+}
+
+impl FromIterator<Arc<Event>> for Calendar {
+    fn from_iter<I: IntoIterator<Item = Arc<Event>>>(iter: I) -> Self {
+        let mut calendar = Self::new();
+
+        for event in iter {
+            calendar.insert(event);
         }
 
-        Ok(Self { tree, uid_index })
+        calendar
     }
 }
 
@@ -53,20 +75,37 @@ impl Serialize for Calendar {
     }
 }
 
+/// Returns the key of an event in the time index
+fn time_key(event: &Event) -> TimeKey {
+    (event.start, event.uid.clone())
+}
+
 impl Calendar {
     pub fn get_range(&self, date: DateTime<Utc>, duration: Duration) -> Vec<Arc<Event>> {
         // get all the events using the tree map
         // this is fast because we just search the binary tree (=few comparaisons to get to the leaf node containing the pointer to the calendar event)
         // and only do a inorder traversal until the upper limit of the range is reached.
+        // the empty uid is the smallest key of an instant, so the range covers all the events starting in [date, date + duration[
         let search = self
             .tree
-            .range(date..date.add(duration))
+            .range((date, String::new())..(date.add(duration), String::new()))
             .map(|f| f.1.clone())
             .collect();
 
         search
     }
 
+    /// Returns the events overlapping `[from, to[`, including the events which started before
+    /// `from` and are still running (e.g. a week of exams).
+    pub fn get_overlapping(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<Arc<Event>> {
+        self.tree
+            .range(..(to, String::new()))
+            .map(|(_, event)| event)
+            .filter(|event| event.end > from)
+            .cloned()
+            .collect()
+    }
+
     pub fn new() -> Self {
         Self {
             tree: BTreeMap::new(),
@@ -74,88 +113,122 @@ impl Calendar {
         }
     }
 
+    /// Returns the event with the given uid
+    pub fn get(&self, uid: &str) -> Option<&Arc<Event>> {
+        self.uid_index.get(uid)
+    }
+
+    /// Returns the start of the last known occurrence of each recurring event
+    fn series_ends(&self) -> HashMap<String, DateTime<Utc>> {
+        let mut ends: HashMap<String, DateTime<Utc>> = HashMap::new();
+
+        for event in self.uid_index.values() {
+            if let Some(series) = series_uid(&event.uid) {
+                let end = ends.entry(series.to_string()).or_insert(event.start);
+                *end = (*end).max(event.start);
+            }
+        }
+
+        ends
+    }
+
+    /// Inserts an event in both indexes, replacing the event with the same uid.
+    fn insert(&mut self, event: Arc<Event>) -> Option<Arc<Event>> {
+        let old = self.remove(&event.uid);
+
+        self.tree.insert(time_key(&event), event.clone());
+        self.uid_index.insert(event.uid.clone(), event);
+
+        old
+    }
+
+    /// Removes an event from both indexes using its uid.
+    fn remove(&mut self, uid: &str) -> Option<Arc<Event>> {
+        let old = self.uid_index.remove(uid)?;
+        self.tree.remove(&time_key(&old));
+
+        Some(old)
+    }
+
     /// Updates an event in a calendar
     /// Returns a list of edits made by the program to match the given calendar
+    /// `bootstrap` tells if it's the first import of the calendar, its creations follow the
+    /// `announce_bootstrap` policy.
     /// WIP: This algorithm needs heavy optimization and is used only for testing purposes
     pub fn update(
         &mut self,
         events: Vec<Event>,
         fetch_time: DateTime<Utc>,
         config: &CalendarItem,
+        bootstrap: bool,
     ) -> Result<Vec<UpdateResult>, anyhow::Error> {
-        // use a tree of the indexed data for better handling
-        let tree_index = BTreeMap::from_iter(events.into_iter().map(|f| {
-            info!("Indexing event at {}", f.start);
-            (f.start, Arc::new(f))
-        }));
-        info!("Updating calendar with {} events", tree_index.len());
-
-        // compute the last event stored in the current calendar
-        let existing_end = *self
+        // index the new events by uid, the first event wins if the source has duplicated uids
+        let mut uid_index: HashMap<String, Arc<Event>> = HashMap::new();
+        for event in events {
+            if uid_index.contains_key(&event.uid) {
+                debug!("ignoring a duplicated event with the uid {}", event.uid);
+            } else {
+                uid_index.insert(event.uid.clone(), Arc::new(event));
+            }
+        }
+
+        // process the events in chronological order
+        let mut sorted: Vec<&Arc<Event>> = uid_index.values().collect();
+        sorted.sort_by(|a, b| (a.start, &a.uid).cmp(&(b.start, &b.uid)));
+        info!("Updating calendar with {} events", sorted.len());
+
+        let end_slice = fetch_time + config.time_range()?;
+        // the known range ends with the last event stored in the range of the fetch,
+        // the events published far ahead don't extend it
+        let known_end = self
             .tree
-            .iter()
-            .next()
-            .map_or(&DateTime::<Utc>::MAX_UTC, |f| f.0);
+            .range(..(end_slice, String::new()))
+            .next_back()
+            .map(|(key, _)| key.0);
+        let series_end = self.series_ends();
 
         let mut updates = vec![];
 
         // for each event we want to add
-        for new in tree_index.values() {
+        for new in sorted {
             info!("1a: Processing event at {}", new.start);
             // if the event already exists, we want to update the event and emit an event
-            if self.uid_index.contains_key(&new.uid) {
-                let existing = self
-                    .uid_index
-                    .get_mut(&new.uid)
-                    .context("expected an event to be in the uid_index, but it wasn't present")?;
-
+            if let Some(existing) = self.uid_index.get(&new.uid) {
                 // if the event is different, we want to update it
+                // (this includes an event switching between timed and all-day)
                 if existing != new {
-                    let old = existing.clone();
-                    // update the uid index
-                    *existing = new.clone();
-                    // update in the tree
-                    self.tree.insert(new.start, new.clone());
-                    self.tree.remove(&old.start);
-
-                    // emit the event
-                    updates.push(UpdateResult::Updated {
-                        old,
-                        new: new.clone(),
-                    });
+                    let old = self.insert(new.clone()).context(
+                        "expected an event to be in the uid_index, but it wasn't present",
+                    )?;
+
+                    // emit the event, a cancellation has its own notification
+                    if new.is_cancelled() && !old.is_cancelled() {
+                        updates.push(UpdateResult::Cancelled(new.clone()));
+                    } else {
+                        updates.push(UpdateResult::updated(old, new.clone()));
+                    }
                 }
             } else {
                 // we want to create the event
 
                 info!("adding new event at {}", new.start);
-
-                let uid = new.uid.clone();
-                self.uid_index.insert(uid, new.clone());
-                self.tree.insert(new.start, new.clone());
-
-                // we should emit an update only if the event is added before the last event present at the start.
-                if new.start < existing_end {
-                    updates.push(UpdateResult::Created(new.clone()));
+                self.insert(new.clone());
+
+                // an event which is already cancelled is not worth a notification.
+                if new.is_cancelled() {
+                    debug!(
+                        "not emitting a created event for {} because it's cancelled",
+                        new.uid
+                    );
                 } else {
-                    debug!("not emitting a created event for {} because it's after the last event present at the start ({})", new.start, existing_end);
+                    updates.push(UpdateResult::Created(new.clone()));
                 }
             }
         }
 
-        let end_slice = fetch_time
-            + Duration::from_std(
-                humantime::parse_duration(&config.time_amount)
-                    .context("invalid format in the time_amount duration")?,
-            )
-            .context("failed to get a duration from standard")?;
-
         // we get all the events present in the range [add_start,add_end]
         // this is used to check if there are events that were deleted
-        let range: Vec<Arc<Event>> = self
-            .tree
-            .range(fetch_time..end_slice)
-            .map(|f| f.1.clone())
-            .collect();
+        let range = self.get_range(fetch_time, end_slice - fetch_time);
 
         info!(
             "Processing {} events [{} - {}]",
@@ -166,27 +239,166 @@ impl Calendar {
 
         // now we are going to check if there are deleted events in the stored range
         for event in range {
-            if !tree_index.contains_key(&event.start) {
+            if !uid_index.contains_key(&event.uid) {
                 // event need to be removed
-                self.tree.remove(&event.start);
                 let old = self
-                    .uid_index
                     .remove(&event.uid)
                     .context("should happen. the key wasn't in the hashmap")?;
 
-                updates.push(UpdateResult::Removed(old));
+                // the cancellation was already announced
+                if !old.is_cancelled() {
+                    updates.push(UpdateResult::Removed(old));
+                }
+            }
+        }
+
+        // the creations may be removed events whose uid changed
+        let updates = rematch(updates, config.rematch_threshold());
+
+        let policy = &config.creations;
+        let horizon = policy
+            .horizon
+            .and_then(|horizon| Duration::from_std(horizon).ok())
+            .and_then(|horizon| fetch_time.checked_add_signed(horizon));
+
+        Ok(updates
+            .into_iter()
+            .filter(|update| {
+                let UpdateResult::Created(new) = update else {
+                    return true;
+                };
+
+                let ignored = if bootstrap && !policy.announce_bootstrap {
+                    Some("it's the first import")
+                } else if series_uid(&new.uid)
+                    .and_then(|series| series_end.get(series))
+                    .is_some_and(|end| new.start > *end)
+                {
+                    // the recurring events are expanded in a range moving with the time
+                    Some("it's a new occurrence of a known recurring event")
+                } else if policy.known_range_only && known_end.is_some_and(|end| new.start > end) {
+                    // the source published events further ahead, they aren't new
+                    Some("it's after the last event present at the start")
+                } else if horizon.is_some_and(|horizon| new.start >= horizon) {
+                    Some("it's beyond the creation horizon")
+                } else {
+                    None
+                };
+
+                if let Some(reason) = ignored {
+                    debug!(
+                        "not emitting a created event for {} because {reason}",
+                        new.uid
+                    );
+                }
+                ignored.is_none()
+            })
+            .collect())
+    }
+}
+
+/// Weights of the criteria of the similarity of two events, the total is 1
+const SUMMARY_WEIGHT: f64 = 0.4;
+const START_WEIGHT: f64 = 0.3;
+const DURATION_WEIGHT: f64 = 0.2;
+const LOCATION_WEIGHT: f64 = 0.1;
+
+/// Scores how much a created event looks like a removed one, from 0 to 1.
+/// The start score decreases linearly with the distance between the starts, down to 0 after a day.
+#[allow(clippy::cast_precision_loss)]
+fn similarity(old: &Event, new: &Event) -> f64 {
+    let distance = (new.start - old.start).num_minutes().unsigned_abs() as f64;
+    let start = (1.0 - distance / Duration::days(1).num_minutes() as f64).max(0.0);
+
+    START_WEIGHT.mul_add(
+        start,
+        [
+            (SUMMARY_WEIGHT, old.summary == new.summary),
+            (DURATION_WEIGHT, old.end - old.start == new.end - new.start),
+            (LOCATION_WEIGHT, old.location == new.location),
+        ]
+        .into_iter()
+        .filter_map(|(weight, matches)| matches.then_some(weight))
+        .sum(),
+    )
+}
+
+/// Pairs the removed and created events of an update that look like the same event,
+/// because some sources (ADE) change the uid of the edited events.
+/// The pairs scoring at least `threshold` become a single `Updated`, the best pairs first.
+fn rematch(updates: Vec<UpdateResult>, threshold: f64) -> Vec<UpdateResult> {
+    let mut pairs = vec![];
+    for (created, update) in updates.iter().enumerate() {
+        let UpdateResult::Created(new) = update else {
+            continue;
+        };
+        for (removed, update) in updates.iter().enumerate() {
+            if let UpdateResult::Removed(old) = update {
+                let score = similarity(old, new);
+                if score >= threshold {
+                    pairs.push((score, created, removed));
+                }
             }
         }
+    }
+    if pairs.is_empty() {
+        return updates;
+    }
+    pairs.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    let mut updates: Vec<Option<UpdateResult>> = updates.into_iter().map(Some).collect();
+    for (score, created, removed) in pairs {
+        let (Some(UpdateResult::Created(new)), Some(UpdateResult::Removed(old))) =
+            (&updates[created], &updates[removed])
+        else {
+            // one of the events was already paired
+            continue;
+        };
+        info!(
+            "pairing the removed event {} with the created event {} (score {score:.2})",
+            old.uid, new.uid
+        );
 
-        Ok(updates)
+        // the update takes the place of the creation, in chronological order
+        updates[created] = Some(UpdateResult::updated(old.clone(), new.clone()));
+        updates[removed] = None;
     }
+
+    updates.into_iter().flatten().collect()
 }
 
 pub type Data = HashMap<String, Calendar>;
 
+/// Cache of the sources of the calendars
+pub type Sources = HashMap<String, SourceState>;
+
+/// Changes waiting for the end of their settle period, by calendar
+pub type PendingData = HashMap<String, PendingChanges>;
+
+/// An update held back by the mass-deletion safeguard of its calendar
+#[derive(Debug, Clone)]
+pub struct Quarantine {
+    /// Events of the fetch
+    pub events: Vec<Event>,
+    pub fetch_time: DateTime<Utc>,
+    /// Number of events removed by the update
+    pub removals: usize,
+    /// Number of events of the checked range before the update
+    pub total: usize,
+    /// Number of consecutive fetches showing the removals
+    pub fetches: u32,
+}
+
 #[derive(Debug)]
 pub struct Store {
     pub data: Data,
+    pub sources: Sources,
+    /// Quarantined updates, by calendar
+    pub quarantine: HashMap<String, Quarantine>,
+    /// Changes waiting for the end of their settle period, by calendar
+    pending: PendingData,
+    /// Changes announced for the calendars
+    pub history: History,
     config: Arc<Config>,
     save_path: String,
 }
@@ -200,40 +412,79 @@ impl Store {
         )
         .to_string();
 
-        match fs::read(&path) {
-            Ok(r) => Ok(Self {
-                data: postcard::from_bytes(&r)?,
-                config,
+        let Some((version, database)) =
+            database::load(&path, config.storage.snapshots, &config.calendar.calendars)?
+        else {
+            // the database doesn't exist yet
+            return Ok(Self {
+                data: Data::default(),
+                sources: Sources::default(),
+                history: History::default(),
+                quarantine: HashMap::new(),
+                pending: PendingData::new(),
                 save_path: path,
-            }),
-            Err(err) => match err.kind() {
-                // The only case where we can accept an error is when the db does not exists
-                io::ErrorKind::NotFound => Ok(Self {
-                    data: Data::default(),
-                    save_path: path,
-                    config,
-                }),
-                _ => bail!(err),
-            },
+                config,
+            });
+        };
+
+        let store = Self {
+            data: database.data,
+            sources: database.sources,
+            history: database.history,
+            quarantine: HashMap::new(),
+            pending: database.pending,
+            config,
+            save_path: path,
+        };
+
+        // a copy of the old file is kept in case the migration went wrong
+        if version < database::VERSION {
+            let backup = format!("{}.v{version}", store.save_path);
+            fs::copy(&store.save_path, &backup)
+                .with_context(|| format!("failed to back up the database to {backup}"))?;
+            store.persist()?;
+            info!("upgraded the database from the layout v{version}, the old file is kept in {backup}");
         }
+
+        Ok(store)
+    }
+
+    /// Writes the calendars, the cache of their sources, the history
+    /// and the changes waiting for their settle period to the disk
+    pub fn persist(&self) -> Result<(), anyhow::Error> {
+        let data = database::encode(&self.data, &self.sources, &self.history, &self.pending)?;
+        database::write(&self.save_path, &data, self.config.storage.snapshots)
     }
 
+    /// Applies the events of a fetch to a calendar, the caller persists the db.
+    /// Returns `None` when the update was quarantined by the safeguard of the calendar.
     pub fn apply(
         &mut self,
         calendar: &str,
         events: Vec<Event>,
         fetch_time: DateTime<Utc>,
-    ) -> Result<Vec<UpdateResult>, anyhow::Error> {
-        let cal = if let Some(calendar) = self.data.get_mut(calendar) {
-            calendar
-        } else {
-            debug!("init: calendar: {}", calendar);
-            self.data.insert(calendar.to_string(), Calendar::new());
+    ) -> Result<Option<Vec<UpdateResult>>, anyhow::Error> {
+        self.update(calendar, events, fetch_time, true)
+    }
 
-            self.data
-                .get_mut(calendar)
-                .context("couldn't insert the calendar in the hashmap")?
-        };
+    /// Applies a quarantined update, this is used when an admin confirms the removals.
+    pub fn confirm(&mut self, calendar: &str) -> Result<Vec<UpdateResult>, anyhow::Error> {
+        let quarantine = self
+            .quarantine
+            .remove(calendar)
+            .context("no update of this calendar is quarantined")?;
+
+        self.update(calendar, quarantine.events, quarantine.fetch_time, false)?
+            .context("the confirmed update was quarantined: unreachable")
+    }
+
+    fn update(
+        &mut self,
+        calendar: &str,
+        events: Vec<Event>,
+        fetch_time: DateTime<Utc>,
+        safeguard: bool,
+    ) -> Result<Option<Vec<UpdateResult>>, anyhow::Error> {
         let config = self
             .config
             .calendar
@@ -241,298 +492,519 @@ impl Store {
             .get(calendar)
             .context("unknown calendar: unreachable")?
             .clone();
+
+        // the update is computed on a copy, so it can be held back.
+        // a calendar that was never stored is imported for the first time, even if a
+        // stored calendar can be empty
+        let bootstrap = !self.data.contains_key(calendar);
+        let mut cal = self.data.get(calendar).cloned().unwrap_or_else(|| {
+            debug!("init: calendar: {}", calendar);
+            Calendar::new()
+        });
+        let total = cal.get_range(fetch_time, config.time_range()?).len();
+        let quarantined = safeguard.then(|| events.clone());
         // Returned updates values
-        let value = cal.update(events, fetch_time, &config)?;
+        let value = cal.update(events, fetch_time, &config, bootstrap)?;
+
+        let removals = value
+            .iter()
+            .filter(|update| matches!(update, UpdateResult::Removed(_)))
+            .count();
+        if let Some(events) = quarantined.filter(|_| config.safeguard.is_exceeded(removals, total))
+        {
+            let fetches = self
+                .quarantine
+                .get(calendar)
+                .map_or(0, |quarantine| quarantine.fetches)
+                + 1;
+
+            if fetches <= config.safeguard.confirmations {
+                warn!("quarantined the update of {calendar}: {removals} of {total} events removed");
+                self.quarantine.insert(
+                    calendar.to_string(),
+                    Quarantine {
+                        events,
+                        fetch_time,
+                        removals,
+                        total,
+                        fetches,
+                    },
+                );
+                return Ok(None);
+            }
+            info!("the removals of {calendar} were confirmed by {fetches} fetches");
+        }
 
-        // Persist the db
-        let data = postcard::to_allocvec(&self.data)?;
-        fs::write(&self.save_path, data)?;
+        self.quarantine.remove(calendar);
+        let previous = self.data.insert(calendar.to_string(), cal);
+
+        let updates = if config.settle.is_enabled() {
+            let pending = self.pending.entry(calendar.to_string()).or_default();
+            settle::record(pending, previous.as_ref(), value, fetch_time);
+            settle::publish(pending, &self.data[calendar], fetch_time, &config.settle)
+        } else {
+            value
+        };
 
-        Ok(value)
+        Ok(Some(updates))
+    }
+
+    /// Adds the updates of the calendars to the history, within its retention
+    pub fn record(&mut self, updates: &HashMap<String, Vec<UpdateResult>>, date: DateTime<Utc>) {
+        for (calendar, updates) in updates {
+            self.history.record(calendar, date, updates);
+        }
+        self.history.prune(date, &self.config.storage.history);
+    }
+
+    /// Publishes the pending changes of a calendar whose source didn't change.
+    /// This counts as a fetch for their settle period.
+    pub fn tick(&mut self, calendar: &str, fetch_time: DateTime<Utc>) -> Vec<UpdateResult> {
+        let (Some(pending), Some(current), Some(config)) = (
+            self.pending.get_mut(calendar),
+            self.data.get(calendar),
+            self.config.calendar.calendars.get(calendar),
+        ) else {
+            return vec![];
+        };
+
+        settle::publish(pending, current, fetch_time, &config.settle)
     }
 }
 
-// #[cfg(test)]
-// mod test {
-//     use std::sync::Arc;
-//
-//     use chrono::{DateTime, NaiveDateTime, Utc};
-//     use poise::serenity_prelude::{ChannelId, RoleId};
-//
-//     use crate::cfg::CalendarItem;
-//
-//     use super::{Calendar, Event, UpdateResult};
-//
-//     #[test]
-//     fn add_events() {
-//         // use a calendar with two weeks checks
-//         let mut cal: Calendar = Calendar::new();
-//         let conf = CalendarItem {
-//             source: String::default(),
-//             channel: vec![ChannelId::new(0)],
-//             role: vec![RoleId::new(0)],
-//             time_amount: "2w".to_string(),
-//         };
-//
-//         let test_events = vec![
-//             Event {
-//                 summary: "test event1".to_string(),
-//                 start: DateTime::from_timestamp(0, 0).unwrap(),
-//                 end: DateTime::from_timestamp(60, 0).unwrap(),
-//                 location: "".to_string(),
-//                 description: "".to_string(),
-//                 uid: "000".to_string(),
-//             },
-//             Event {
-//                 summary: "test event1".to_string(),
-//                 start: DateTime::from_timestamp(60, 0).unwrap(),
-//                 end: DateTime::from_timestamp(120, 0).unwrap(),
-//                 location: "".to_string(),
-//                 description: "".to_string(),
-//                 uid: "002".to_string(),
-//             },
-//         ];
-//
-//         let updates = cal
-//             .update(
-//                 test_events.clone(),
-//                 DateTime::<Utc>::from_timestamp(0, 0).unwrap(),
-//                 &conf,
-//             )
-//             .unwrap();
-//
-//         let expected = vec![
-//             UpdateResult::Created(Arc::new(test_events[0].clone())),
-//             UpdateResult::Created(Arc::new(test_events[1].clone())),
-//         ];
-//
-//         assert_eq!(updates, expected);
-//     }
-//
-//     #[test]
-//     fn edit_events() {
-//         let mut cal: Calendar = Calendar::new();
-//
-//         let conf = CalendarItem {
-//             source: String::default(),
-//             channel: vec![ChannelId::new(0)],
-//             role: vec![RoleId::new(0)],
-//             time_amount: "2w".to_string(),
-//         };
-//
-//         let test_events = vec![
-//             Event {
-//                 summary: "test event1".to_string(),
-//                 start: DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(0, 0), Utc),
-//                 end: DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(60, 0), Utc),
-//                 location: "".to_string(),
-//                 description: "".to_string(),
-//                 uid: "000".to_string(),
-//             },
-//             Event {
-//                 summary: "test event1".to_string(),
-//                 start: DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(60, 0), Utc),
-//                 end: DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(120, 0), Utc),
-//                 location: "".to_string(),
-//                 description: "".to_string(),
-//                 uid: "002".to_string(),
-//             },
-//         ];
-//
-//         let inserts = cal
-//             .update(
-//                 test_events.clone(),
-//                 NaiveDateTime::from_timestamp(0, 0),
-//                 &conf,
-//             )
-//             .unwrap();
-//
-//         let expected = vec![
-//             UpdateResult::Created(Arc::new(test_events[0].clone())),
-//             UpdateResult::Created(Arc::new(test_events[1].clone())),
-//         ];
-//
-//         assert_eq!(inserts, expected);
-//
-//         let updates_data = vec![
-//             Event {
-//                 summary: "test event1".to_string(),
-//                 start: NaiveDateTime::from_timestamp_opt(0, 0).unwrap(),
-//                 end: NaiveDateTime::from_timestamp_opt(60, 0).unwrap(),
-//                 location: "".to_string(),
-//                 description: "this is updated".to_string(),
-//                 uid: "000".to_string(),
-//             },
-//             Event {
-//                 summary: "test event1".to_string(),
-//                 start: NaiveDateTime::from_timestamp_opt(65, 0).unwrap(),
-//                 end: NaiveDateTime::from_timestamp_opt(120, 0).unwrap(),
-//                 location: "".to_string(),
-//                 description: "this is updated".to_string(),
-//                 uid: "002".to_string(),
-//             },
-//         ];
-//
-//         let updates = cal
-//             .update(
-//                 updates_data.clone(),
-//                 NaiveDateTime::from_timestamp(0, 0),
-//                 &conf,
-//             )
-//             .unwrap();
-//
-//         let expected = vec![
-//             UpdateResult::Updated {
-//                 old: Arc::new(test_events[0].clone()),
-//                 new: Arc::new(updates_data[0].clone()),
-//             },
-//             UpdateResult::Updated {
-//                 old: Arc::new(test_events[1].clone()),
-//                 new: Arc::new(updates_data[1].clone()),
-//             },
-//         ];
-//
-//         assert_eq!(updates, expected);
-//     }
-//
-//     #[test]
-//     fn remove_test() {
-//         let mut cal: Calendar = Calendar::new();
-//
-//         let conf = CalendarItem {
-//             source: String::default(),
-//             channel: vec![ChannelId::new(0)],
-//             role: vec![RoleId::new(0)],
-//             time_amount: "2w".to_string(),
-//         };
-//
-//         let test_events = vec![
-//             Event {
-//                 summary: "test event1".to_string(),
-//                 start: NaiveDateTime::from_timestamp(0, 0),
-//                 end: NaiveDateTime::from_timestamp(60, 0),
-//                 location: "".to_string(),
-//                 description: "".to_string(),
-//                 uid: "000".to_string(),
-//             },
-//             Event {
-//                 summary: "test event2".to_string(),
-//                 start: NaiveDateTime::from_timestamp(60, 0),
-//                 end: NaiveDateTime::from_timestamp(120, 0),
-//                 location: "".to_string(),
-//                 description: "".to_string(),
-//                 uid: "002".to_string(),
-//             },
-//             Event {
-//                 summary: "test event3".to_string(),
-//                 start: NaiveDateTime::from_timestamp(120, 0),
-//                 end: NaiveDateTime::from_timestamp(180, 0),
-//                 location: "".to_string(),
-//                 description: "".to_string(),
-//                 uid: "003".to_string(),
-//             },
-//         ];
-//
-//         cal.update(
-//             test_events.clone(),
-//             NaiveDateTime::from_timestamp_opt(0, 0).unwrap(),
-//             &conf,
-//         )
-//         .unwrap();
-//
-//         let updates_data = vec![];
-//
-//         let updates = cal
-//             .update(
-//                 updates_data,
-//                 NaiveDateTime::from_timestamp_opt(0, 0).unwrap(),
-//                 &conf,
-//             )
-//             .unwrap();
-//
-//         let expected = vec![
-//             UpdateResult::Removed(Arc::new(test_events[0].clone())),
-//             UpdateResult::Removed(Arc::new(test_events[1].clone())),
-//             UpdateResult::Removed(Arc::new(test_events[2].clone())),
-//         ];
-//
-//         assert_eq!(updates, expected);
-//     }
-//
-//     #[test]
-//     fn remove_test_2() {
-//         let mut cal: Calendar = Calendar::new();
-//
-//         let conf = CalendarItem {
-//             source: String::default(),
-//             channel: vec![ChannelId::new(0)],
-//             role: vec![RoleId::new(0)],
-//             time_amount: "2w".to_string(),
-//         };
-//         let test_events = vec![
-//             Event {
-//                 summary: "test event1".to_string(),
-//                 start: DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(0, 0), Utc),
-//                 end: DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(60, 0), Utc),
-//                 location: "".to_string(),
-//                 description: "".to_string(),
-//                 uid: "000".to_string(),
-//             },
-//             Event {
-//                 summary: "test event2".to_string(),
-//                 start: DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(60, 0), Utc),
-//                 end: DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(120, 0), Utc),
-//                 location: "".to_string(),
-//                 description: "".to_string(),
-//                 uid: "002".to_string(),
-//             },
-//             Event {
-//                 summary: "test event3".to_string(),
-//                 start: DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(120, 0), Utc),
-//                 end: DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(180, 0), Utc),
-//                 location: "".to_string(),
-//                 description: "".to_string(),
-//                 uid: "003".to_string(),
-//             },
-//         ];
-//
-//         cal.update(
-//             test_events.clone(),
-//             DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(0, 0), Utc),
-//             &conf,
-//         )
-//         .unwrap();
-//
-//         let updates_data = vec![
-//             Event {
-//                 summary: "test event1".to_string(),
-//                 start: DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(0, 0), Utc),
-//                 end: DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(60, 0), Utc),
-//                 location: "".to_string(),
-//                 description: "".to_string(),
-//                 uid: "000".to_string(),
-//             },
-//             Event {
-//                 summary: "test event3".to_string(),
-//                 start: DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(120, 0), Utc),
-//                 end: DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(180, 0), Utc),
-//                 location: "".to_string(),
-//                 description: "".to_string(),
-//                 uid: "003".to_string(),
-//             },
-//         ];
-//
-//         let updates = cal
-//             .update(
-//                 updates_data,
-//                 DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(0, 0), Utc),
-//                 &conf,
-//             )
-//             .unwrap();
-//
-//         let expected = vec![UpdateResult::Removed(Arc::new(test_events[1].clone()))];
-//
-//         assert_eq!(updates, expected);
-//     }
-// }
-//
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use chrono::{DateTime, Duration, Utc};
+    use poise::serenity_prelude::{ChannelId, RoleId};
+
+    use crate::{
+        calendar::{recurrence::occurrence_uid, Field},
+        cfg::{CalendarItem, Creations},
+    };
+
+    use super::{Calendar, Event, UpdateResult};
+
+    fn config() -> CalendarItem {
+        // use a calendar with two weeks checks
+        CalendarItem {
+            source: String::default(),
+            channel: vec![ChannelId::new(1)],
+            role: vec![RoleId::new(1)],
+            time_amount: "2w".to_string(),
+            // the tests check the updates of the first import
+            creations: Creations {
+                announce_bootstrap: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn event(summary: &str, start: i64, end: i64, uid: &str) -> Event {
+        Event {
+            summary: summary.to_string(),
+            start: DateTime::from_timestamp(start, 0).unwrap(),
+            end: DateTime::from_timestamp(end, 0).unwrap(),
+            uid: uid.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn epoch() -> DateTime<Utc> {
+        DateTime::from_timestamp(0, 0).unwrap()
+    }
+
+    #[test]
+    fn add_events() {
+        let mut cal: Calendar = Calendar::new();
+        let conf = config();
+
+        let test_events = vec![
+            event("test event1", 0, 60, "000"),
+            event("test event1", 60, 120, "002"),
+        ];
+
+        let updates = cal
+            .update(test_events.clone(), epoch(), &conf, true)
+            .unwrap();
+
+        let expected = vec![
+            UpdateResult::Created(Arc::new(test_events[0].clone())),
+            UpdateResult::Created(Arc::new(test_events[1].clone())),
+        ];
+
+        assert_eq!(updates, expected);
+    }
+
+    #[test]
+    fn edit_events() {
+        let mut cal: Calendar = Calendar::new();
+        let conf = config();
+
+        let test_events = vec![
+            event("test event1", 0, 60, "000"),
+            event("test event1", 60, 120, "002"),
+        ];
+
+        let inserts = cal
+            .update(test_events.clone(), epoch(), &conf, true)
+            .unwrap();
+
+        let expected = vec![
+            UpdateResult::Created(Arc::new(test_events[0].clone())),
+            UpdateResult::Created(Arc::new(test_events[1].clone())),
+        ];
+
+        assert_eq!(inserts, expected);
+
+        let mut updates_data = vec![
+            event("test event1", 0, 60, "000"),
+            event("test event1", 65, 120, "002"),
+        ];
+        for event in &mut updates_data {
+            event.description = "this is updated".to_string();
+        }
+
+        let updates = cal
+            .update(updates_data.clone(), epoch(), &conf, false)
+            .unwrap();
+
+        let expected = vec![
+            UpdateResult::Updated {
+                old: Arc::new(test_events[0].clone()),
+                new: Arc::new(updates_data[0].clone()),
+                changes: vec![Field::Description],
+            },
+            UpdateResult::Updated {
+                old: Arc::new(test_events[1].clone()),
+                new: Arc::new(updates_data[1].clone()),
+                changes: vec![Field::Start, Field::Description],
+            },
+        ];
+
+        assert_eq!(updates, expected);
+        // the updated events must still be in the time index
+        assert_eq!(cal.get_range(epoch(), Duration::days(1)).len(), 2);
+    }
+
+    #[test]
+    fn remove_test() {
+        let mut cal: Calendar = Calendar::new();
+        let conf = config();
+
+        let test_events = vec![
+            event("test event1", 0, 60, "000"),
+            event("test event2", 60, 120, "002"),
+            event("test event3", 120, 180, "003"),
+        ];
+
+        cal.update(test_events.clone(), epoch(), &conf, true)
+            .unwrap();
+
+        let updates = cal.update(vec![], epoch(), &conf, false).unwrap();
+
+        let expected = vec![
+            UpdateResult::Removed(Arc::new(test_events[0].clone())),
+            UpdateResult::Removed(Arc::new(test_events[1].clone())),
+            UpdateResult::Removed(Arc::new(test_events[2].clone())),
+        ];
+
+        assert_eq!(updates, expected);
+    }
+
+    #[test]
+    fn overlapping_events() {
+        let cal: Calendar = [
+            // a week of exams which started before the range
+            event("exams", -86400, 6 * 86400, "000"),
+            event("ended", -7200, -3600, "001"),
+            event("course", 3600, 7200, "002"),
+            event("later", 86400, 90000, "003"),
+        ]
+        .into_iter()
+        .map(Arc::new)
+        .collect();
+
+        let uids: Vec<String> = cal
+            .get_overlapping(epoch(), epoch() + Duration::days(1))
+            .iter()
+            .map(|event| event.uid.clone())
+            .collect();
+        assert_eq!(uids, vec!["000", "002"]);
+    }
+
+    #[test]
+    fn remove_test_2() {
+        let mut cal: Calendar = Calendar::new();
+        let conf = config();
+
+        let test_events = vec![
+            event("test event1", 0, 60, "000"),
+            event("test event2", 60, 120, "002"),
+            event("test event3", 120, 180, "003"),
+        ];
+
+        cal.update(test_events.clone(), epoch(), &conf, true)
+            .unwrap();
+
+        let updates_data = vec![
+            event("test event1", 0, 60, "000"),
+            event("test event3", 120, 180, "003"),
+        ];
+
+        let updates = cal.update(updates_data, epoch(), &conf, false).unwrap();
+
+        let expected = vec![UpdateResult::Removed(Arc::new(test_events[1].clone()))];
+
+        assert_eq!(updates, expected);
+    }
+
+    #[test]
+    fn same_start_events() {
+        let mut cal: Calendar = Calendar::new();
+        let conf = config();
+
+        // two groups having a class at the same time
+        let test_events = vec![
+            event("group A", 0, 60, "000"),
+            event("group B", 0, 60, "001"),
+        ];
+
+        let updates = cal
+            .update(test_events.clone(), epoch(), &conf, true)
+            .unwrap();
+
+        assert_eq!(updates.len(), 2);
+        assert_eq!(cal.get_range(epoch(), Duration::days(1)).len(), 2);
+
+        // removing one of them must not remove the other one
+        let updates = cal
+            .update(vec![test_events[0].clone()], epoch(), &conf, false)
+            .unwrap();
+
+        let expected = vec![UpdateResult::Removed(Arc::new(test_events[1].clone()))];
+
+        assert_eq!(updates, expected);
+        assert_eq!(
+            cal.get_range(epoch(), Duration::days(1)),
+            vec![Arc::new(test_events[0].clone())]
+        );
+    }
+
+    #[test]
+    fn same_start_replaced() {
+        let mut cal: Calendar = Calendar::new();
+        let conf = config();
+
+        let test_events = vec![
+            event("test event1", 0, 60, "000"),
+            event("exam room 1", 60, 120, "001"),
+        ];
+
+        cal.update(test_events.clone(), epoch(), &conf, true)
+            .unwrap();
+
+        // the event at the same instant is replaced by another one (new uid)
+        let replacement = event("exam room 2", 60, 120, "002");
+        let updates = cal
+            .update(
+                vec![test_events[0].clone(), replacement.clone()],
+                epoch(),
+                &conf,
+                false,
+            )
+            .unwrap();
+
+        assert!(updates.contains(&UpdateResult::Removed(Arc::new(test_events[1].clone()))));
+        assert_eq!(
+            cal.get_range(epoch(), Duration::days(1)),
+            vec![Arc::new(test_events[0].clone()), Arc::new(replacement)]
+        );
+    }
+
+    #[test]
+    fn rematch_changed_uid() {
+        let mut cal: Calendar = Calendar::new();
+        let conf = config();
+
+        let mut lecture = event("test event1", 0, 3600, "000");
+        lecture.location = "room 1".to_string();
+        let other = event("test event2", 7200, 10800, "001");
+        cal.update(vec![lecture.clone(), other.clone()], epoch(), &conf, true)
+            .unwrap();
+
+        // the source moved the lecture to another room with a new uid,
+        // and replaced the other event by an unrelated one
+        let mut moved = event("test event1", 0, 3600, "100");
+        moved.location = "room 2".to_string();
+        let unrelated = event("test event3", 7200, 9000, "101");
+        let updates = cal
+            .update(vec![moved.clone(), unrelated], epoch(), &conf, false)
+            .unwrap();
+
+        assert_eq!(
+            updates[0],
+            UpdateResult::Updated {
+                old: Arc::new(lecture),
+                new: Arc::new(moved),
+                changes: vec![Field::Location],
+            }
+        );
+        assert!(updates.contains(&UpdateResult::Removed(Arc::new(other))));
+        assert!(!updates
+            .iter()
+            .any(|update| matches!(update, UpdateResult::Removed(event) if event.uid == "000")));
+    }
+
+    #[test]
+    fn silent_bootstrap() {
+        let mut cal: Calendar = Calendar::new();
+        let conf = CalendarItem {
+            time_amount: "2w".to_string(),
+            ..Default::default()
+        };
+
+        let test_events = vec![
+            event("test event1", 0, 60, "000"),
+            event("test event2", 7200, 7260, "001"),
+        ];
+        let updates = cal
+            .update(test_events.clone(), epoch(), &conf, true)
+            .unwrap();
+
+        assert!(updates.is_empty());
+        assert_eq!(cal.get_range(epoch(), Duration::days(1)).len(), 2);
+
+        // the next creations are announced
+        let created = event("test event3", 3600, 3660, "002");
+        let mut next_events = test_events;
+        next_events.push(created.clone());
+        let updates = cal.update(next_events, epoch(), &conf, false).unwrap();
+
+        assert_eq!(updates, vec![UpdateResult::Created(Arc::new(created))]);
+    }
+
+    #[test]
+    fn last_known_event() {
+        let mut cal: Calendar = Calendar::new();
+        let conf = config();
+
+        let mut test_events = vec![
+            event("test event1", 0, 60, "000"),
+            event("test event2", 7200, 7260, "001"),
+        ];
+        cal.update(test_events.clone(), epoch(), &conf, true)
+            .unwrap();
+
+        // the events after the known events are announced, even far ahead
+        let inside = event("test event3", 3600, 3660, "002");
+        let ahead = event("test event4", 10800, 10860, "003");
+        let far = event("test event5", 90 * 86400, 90 * 86400 + 60, "004");
+        test_events.extend([inside.clone(), ahead.clone(), far.clone()]);
+        let updates = cal.update(test_events, epoch(), &conf, false).unwrap();
+
+        assert_eq!(
+            updates,
+            vec![
+                UpdateResult::Created(Arc::new(inside)),
+                UpdateResult::Created(Arc::new(ahead)),
+                UpdateResult::Created(Arc::new(far)),
+            ]
+        );
+        assert_eq!(cal.get_range(epoch(), Duration::days(1)).len(), 4);
+    }
+
+    #[test]
+    fn known_range_only() {
+        let mut cal: Calendar = Calendar::new();
+        let conf = CalendarItem {
+            creations: Creations {
+                announce_bootstrap: true,
+                known_range_only: true,
+                horizon: None,
+            },
+            ..config()
+        };
+
+        // the far event is after the range checked by the calendar (2 weeks)
+        let mut test_events = vec![
+            event("test event1", 0, 60, "000"),
+            event("test event2", 7200, 7260, "001"),
+            event("test event3", 90 * 86400, 90 * 86400 + 60, "002"),
+        ];
+        cal.update(test_events.clone(), epoch(), &conf, true)
+            .unwrap();
+
+        // an event between the known events is new, an event after them was published ahead
+        let inside = event("test event4", 3600, 3660, "003");
+        let ahead = event("test event5", 10800, 10860, "004");
+        test_events.extend([inside.clone(), ahead]);
+        let updates = cal.update(test_events, epoch(), &conf, false).unwrap();
+
+        assert_eq!(updates, vec![UpdateResult::Created(Arc::new(inside))]);
+    }
+
+    #[test]
+    fn moving_expansion_range() {
+        let mut cal: Calendar = Calendar::new();
+        let conf = config();
+        let occurrence = |series: &str, day: i64| {
+            let start = day * 86400;
+            event(
+                "test event",
+                start,
+                start + 3600,
+                &occurrence_uid(series, DateTime::from_timestamp(start, 0).unwrap()),
+            )
+        };
+
+        // a far event doesn't change the range of the series
+        let mut test_events = vec![
+            occurrence("1", 0),
+            occurrence("1", 2),
+            event("test event2", 90 * 86400, 90 * 86400 + 60, "000"),
+        ];
+        cal.update(test_events.clone(), epoch(), &conf, true)
+            .unwrap();
+
+        // the expansion range moved: the next occurrence isn't new,
+        // unlike an occurrence added inside the series or a new series
+        let added = occurrence("1", 1);
+        let series = occurrence("2", 3);
+        test_events.extend([occurrence("1", 4), added.clone(), series.clone()]);
+        let updates = cal
+            .update(test_events, epoch() + Duration::days(1), &conf, false)
+            .unwrap();
+
+        assert_eq!(
+            updates,
+            vec![
+                UpdateResult::Created(Arc::new(added)),
+                UpdateResult::Created(Arc::new(series)),
+            ]
+        );
+    }
+
+    #[test]
+    fn creation_horizon() {
+        let mut cal: Calendar = Calendar::new();
+        let conf = CalendarItem {
+            creations: Creations {
+                announce_bootstrap: true,
+                known_range_only: false,
+                horizon: Some(std::time::Duration::from_hours(1)),
+            },
+            ..config()
+        };
+
+        let soon = event("test event1", 1800, 1860, "000");
+        let later = event("test event2", 7200, 7260, "001");
+        let updates = cal
+            .update(vec![soon.clone(), later], epoch(), &conf, true)
+            .unwrap();
+
+        assert_eq!(updates, vec![UpdateResult::Created(Arc::new(soon))]);
+    }
+}
@@ -0,0 +1,116 @@
+use std::{collections::HashMap, sync::Arc};
+
+use chrono::{DateTime, Utc};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use crate::cfg::Settle;
+
+use super::{schedule::Calendar, Event, UpdateResult};
+
+/// A change of an event waiting for the end of the settle period
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pending {
+    /// The event as it was last announced, `None` if it didn't exist
+    base: Option<Arc<Event>>,
+    /// When the last change of the event was seen
+    since: DateTime<Utc>,
+    /// Number of fetches since the last change of the event, including it
+    cycles: u32,
+}
+
+/// Changes waiting for the end of their settle period, by uid
+pub type PendingChanges = HashMap<String, Pending>;
+
+/// Returns the event changed by an update, in its latest version
+fn changed_event(update: &UpdateResult) -> &Event {
+    match update {
+        UpdateResult::Created(event)
+        | UpdateResult::Removed(event)
+        | UpdateResult::Cancelled(event)
+        | UpdateResult::Updated { new: event, .. } => event,
+    }
+}
+
+/// Records the changes of a fetch.
+/// A new change of an event which is already pending restarts its settle period,
+/// but keeps the last announced version of the event.
+pub fn record(
+    pending: &mut PendingChanges,
+    previous: Option<&Calendar>,
+    updates: Vec<UpdateResult>,
+    fetch_time: DateTime<Utc>,
+) {
+    for update in updates {
+        let uid = &changed_event(&update).uid;
+        debug!("holding the change of {uid} until it settles: {update:?}");
+
+        pending
+            .entry(uid.clone())
+            .and_modify(|pending| {
+                pending.since = fetch_time;
+                pending.cycles = 0;
+            })
+            .or_insert_with(|| Pending {
+                // the uid of a rematched event changed, its old version isn't under the new uid
+                base: match &update {
+                    UpdateResult::Updated { old, .. } => Some(old.clone()),
+                    _ => previous.and_then(|calendar| calendar.get(uid)).cloned(),
+                },
+                since: fetch_time,
+                cycles: 0,
+            });
+    }
+}
+
+/// Computes the update announcing the change from `base` to `current`
+fn announce(base: Option<Arc<Event>>, current: Option<Arc<Event>>) -> Option<UpdateResult> {
+    match (base, current) {
+        (None, Some(new)) => (!new.is_cancelled()).then_some(UpdateResult::Created(new)),
+        (Some(old), None) => (!old.is_cancelled()).then_some(UpdateResult::Removed(old)),
+        (Some(old), Some(new)) => Some(if new.is_cancelled() && !old.is_cancelled() {
+            UpdateResult::Cancelled(new)
+        } else {
+            UpdateResult::updated(old, new)
+        }),
+        (None, None) => None,
+    }
+}
+
+/// Publishes the changes that persisted for the settle period of the calendar.
+/// The changes reverted in the meantime are dropped, they only appear in the logs.
+pub fn publish(
+    pending: &mut PendingChanges,
+    current: &Calendar,
+    fetch_time: DateTime<Utc>,
+    settle: &Settle,
+) -> Vec<UpdateResult> {
+    let mut updates = vec![];
+
+    pending.retain(|uid, change| {
+        change.cycles += 1;
+        let event = current.get(uid).cloned();
+
+        if event == change.base {
+            info!("dropping the change of {uid}, it was reverted during its settle period");
+            return false;
+        }
+
+        let settled = change.cycles > settle.cycles
+            && (fetch_time - change.since)
+                .to_std()
+                .is_ok_and(|elapsed| elapsed >= settle.time);
+        if settled {
+            updates.extend(announce(change.base.take(), event));
+        }
+
+        !settled
+    });
+
+    updates.sort_by_key(|update| {
+        let event = changed_event(update);
+        (event.start, event.uid.clone())
+    });
+
+    updates
+}
@@ -0,0 +1,354 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, bail, Context};
+use bytes::Bytes;
+use chrono::{DateTime, Duration, Utc};
+use log::{debug, warn};
+use reqwest::{
+    header::{HeaderName, HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    Client, RequestBuilder, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use tokio::time::{error::Elapsed, sleep, timeout};
+
+use crate::cfg::{Auth, CalendarItem, FetchPolicy};
+
+use super::{ade, caldav};
+
+/// Maximum age of the last parsing of a source with recurring events.
+/// The recurring events are expanded in a range that moves with the time,
+/// so these sources are parsed again from time to time even if they didn't change.
+const RECURRING_REFRESH: Duration = Duration::days(1);
+
+/// Cache of the last fetch of a source, persisted with the calendars.
+/// It is used to avoid downloading and parsing a source that didn't change.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceState {
+    /// `ETag` header of the last response
+    pub etag: Option<String>,
+    /// `Last-Modified` header of the last response
+    pub last_modified: Option<String>,
+    /// Sha256 of the last body
+    pub hash: Option<String>,
+    /// When the source was last parsed
+    pub parsed: Option<DateTime<Utc>>,
+    /// The source contains recurring events
+    pub recurring: bool,
+    /// Sync token of a `caldav://` collection
+    pub sync_token: Option<String>,
+    /// Calendar objects of a `caldav://` collection, by href
+    pub objects: BTreeMap<String, String>,
+    /// When the objects of a `caldav://` collection were last read with a time-range query.
+    /// This only lives as long as the bot, a collection is queried again after a restart.
+    #[serde(skip)]
+    pub queried: Option<DateTime<Utc>>,
+    /// Session opened on an ADE server, this only lives as long as the bot
+    #[serde(skip)]
+    pub session: Option<String>,
+    /// The source was parsed since the bot started.
+    /// The settings used to parse the source (timezone, cleanup rules...) are read at startup,
+    /// so the first fetch parses the source again to apply the settings edited meanwhile.
+    #[serde(skip)]
+    pub current: bool,
+}
+
+impl SourceState {
+    /// Checks if the source must be parsed again, even if it didn't change.
+    fn needs_refresh(&self, now: DateTime<Utc>) -> bool {
+        !self.current
+            || (self.recurring
+                && self
+                    .parsed
+                    .is_none_or(|parsed| now - parsed > RECURRING_REFRESH))
+    }
+}
+
+/// Where the events of a calendar are read from.
+/// This is parsed from the `source` of a calendar:
+/// - `http://` and `https://` urls are fetched over http,
+/// - `webcal://` and `webcals://` urls are rewritten to `http://` and `https://`,
+/// - `caldav://` and `caldavs://` urls are calendar collections (RFC 4791), fetched over `http://` and `https://`,
+/// - `ade://` and `ades://` urls are ADE servers, fetched over `http://` and `https://`
+///   using the `ade` settings of the calendar,
+/// - `file://` paths are read from the disk, they can be relative to the working directory
+///   of the bot (`file://exams.ics`). A directory is read as the union of its `.ics` files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    Http(String),
+    CalDav(String),
+    Ade(String),
+    File(PathBuf),
+}
+
+impl FromStr for Source {
+    type Err = anyhow::Error;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let Some((scheme, rest)) = source.split_once("://") else {
+            bail!("the source must be an url: {source}");
+        };
+
+        Ok(match scheme.to_ascii_lowercase().as_str() {
+            "http" | "https" => Self::Http(source.to_string()),
+            "webcal" => Self::Http(format!("http://{rest}")),
+            "webcals" => Self::Http(format!("https://{rest}")),
+            "caldav" => Self::CalDav(format!("http://{rest}")),
+            "caldavs" => Self::CalDav(format!("https://{rest}")),
+            "ade" => Self::Ade(format!("http://{rest}")),
+            "ades" => Self::Ade(format!("https://{rest}")),
+            "file" => Self::File(PathBuf::from(
+                shellexpand::full_with_context_no_errors(
+                    rest,
+                    || dirs::home_dir().and_then(|p| p.to_str().map(ToString::to_string)),
+                    |f| std::env::var(f).ok(),
+                )
+                .as_ref(),
+            )),
+            _ => bail!("unsupported source scheme: {scheme}"),
+        })
+    }
+}
+
+/// Result of the fetch of a source
+pub enum Fetched {
+    /// The source didn't change since the last fetch
+    Unchanged,
+    /// The new content of the source
+    Body(Bytes),
+}
+
+/// Returns the value of a response header as a string
+fn header(response: &reqwest::Response, name: reqwest::header::HeaderName) -> Option<String> {
+    response
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(ToString::to_string)
+}
+
+/// Adds the authentication and the custom headers of a calendar to a request.
+/// The values are marked as sensitive so they are hidden from the debug output of reqwest.
+pub fn authenticate(
+    mut request: RequestBuilder,
+    watch_item: &CalendarItem,
+) -> Result<RequestBuilder, anyhow::Error> {
+    request = match &watch_item.auth {
+        Some(Auth::Basic { username, password }) => {
+            request.basic_auth(username, Some(password.expose()?))
+        }
+        Some(Auth::Bearer { token }) => request.bearer_auth(token.expose()?),
+        None => request,
+    };
+
+    for (name, value) in &watch_item.headers {
+        let name = HeaderName::try_from(name.as_str())
+            .with_context(|| format!("invalid header name: {name}"))?;
+        let mut value = HeaderValue::try_from(value.expose()?)
+            .with_context(|| format!("invalid value for the header {name}"))?;
+        value.set_sensitive(true);
+
+        request = request.header(name, value);
+    }
+
+    Ok(request)
+}
+
+/// Fetches a source over http.
+/// This uses a conditional request (`If-None-Match`/`If-Modified-Since`) unless `refresh` is set.
+/// Returns `None` when the server answered that the source didn't change.
+async fn fetch_http(
+    client: &Client,
+    url: &str,
+    watch_item: &CalendarItem,
+    state: &mut SourceState,
+    refresh: bool,
+) -> Result<Option<Bytes>, anyhow::Error> {
+    let mut request = authenticate(client.get(url), watch_item)?;
+
+    if !refresh {
+        if let Some(etag) = &state.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &state.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+
+    let response = request.send().await?;
+
+    if response.status() == StatusCode::NOT_MODIFIED {
+        debug!("{url} answered not modified");
+        return Ok(None);
+    }
+
+    let response = response.error_for_status()?;
+    state.etag = header(&response, ETAG);
+    state.last_modified = header(&response, LAST_MODIFIED);
+
+    Ok(Some(
+        response
+            .bytes()
+            .await
+            .context("failed to read the body of the source")?,
+    ))
+}
+
+/// Reads a file source.
+/// Directories are read as the concatenation of their `.ics` files, sorted by name.
+async fn read_path(path: &Path) -> Result<Bytes, anyhow::Error> {
+    let metadata = tokio::fs::metadata(path)
+        .await
+        .with_context(|| format!("failed to read {}", path.display()))?;
+
+    if !metadata.is_dir() {
+        return Ok(tokio::fs::read(path)
+            .await
+            .with_context(|| format!("failed to read {}", path.display()))?
+            .into());
+    }
+
+    let mut files = Vec::new();
+    let mut entries = tokio::fs::read_dir(path)
+        .await
+        .with_context(|| format!("failed to list {}", path.display()))?;
+    while let Some(entry) = entries.next_entry().await? {
+        let file = entry.path();
+        if file
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("ics"))
+        {
+            files.push(file);
+        }
+    }
+    files.sort();
+
+    let mut body = Vec::new();
+    for file in files {
+        body.extend(
+            tokio::fs::read(&file)
+                .await
+                .with_context(|| format!("failed to read {}", file.display()))?,
+        );
+        // each file holds its own VCALENDAR block
+        body.push(b'\n');
+    }
+
+    Ok(body.into())
+}
+
+/// Fetches the content of the source of a calendar.
+/// The hash of the content is compared with the last fetch to detect the sources
+/// that didn't change, http sources also use conditional requests and `caldav://` sources
+/// only fetch the objects changed since the last sync.
+pub async fn fetch(
+    client: &Client,
+    watch_item: &CalendarItem,
+    state: &mut SourceState,
+) -> Result<Fetched, anyhow::Error> {
+    let refresh = state.needs_refresh(Utc::now());
+
+    let body = match watch_item.source.parse()? {
+        Source::Http(url) => {
+            let Some(body) = fetch_http(client, &url, watch_item, state, refresh).await? else {
+                return Ok(Fetched::Unchanged);
+            };
+            body
+        }
+        Source::CalDav(url) => {
+            let Some(body) = caldav::fetch(client, &url, watch_item, state, refresh).await? else {
+                return Ok(Fetched::Unchanged);
+            };
+            body
+        }
+        Source::Ade(url) => ade::fetch(client, &url, watch_item, state).await?,
+        Source::File(path) => read_path(&path).await?,
+    };
+    let hash = format!("{:x}", Sha256::digest(&body));
+
+    if !refresh && state.hash.as_ref() == Some(&hash) {
+        debug!("the content of {} didn't change", watch_item.source);
+        return Ok(Fetched::Unchanged);
+    }
+    state.hash = Some(hash);
+
+    Ok(Fetched::Body(body))
+}
+
+/// Checks if an error is worth a retry: a timeout, a connection error
+/// or a status telling the server is overloaded or temporarily broken.
+fn is_transient(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause.is::<Elapsed>()
+            || cause.downcast_ref::<reqwest::Error>().is_some_and(|err| {
+                err.is_timeout()
+                    || err.is_connect()
+                    || err.status().is_some_and(|status| {
+                        status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+                    })
+            })
+    })
+}
+
+/// Delay before a retry: the backoff of the policy doubled at every attempt,
+/// randomized between half and all of it.
+fn retry_delay(policy: &FetchPolicy, attempt: u32) -> std::time::Duration {
+    let delay = policy
+        .backoff
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(policy.max_backoff);
+
+    delay.mul_f64(fastrand::f64().mul_add(0.5, 0.5))
+}
+
+/// Fetches a source using the timeouts and retries of its policy.
+/// Each attempt starts from the given state, which is only updated by the successful attempt.
+pub async fn fetch_with_policy(
+    client: &Client,
+    watch_item: &CalendarItem,
+    policy: &FetchPolicy,
+    state: &mut SourceState,
+) -> Result<Fetched, anyhow::Error> {
+    let mut attempt = 0;
+
+    loop {
+        let mut attempt_state = state.clone();
+        let result = match timeout(
+            policy.timeout,
+            fetch(client, watch_item, &mut attempt_state),
+        )
+        .await
+        {
+            Ok(result) => result,
+            Err(elapsed) => Err(anyhow!(elapsed).context(format!(
+                "the fetch took more than {}",
+                humantime::format_duration(policy.timeout)
+            ))),
+        };
+
+        match result {
+            Ok(fetched) => {
+                *state = attempt_state;
+                return Ok(fetched);
+            }
+            Err(err) if attempt < policy.retries && is_transient(&err) => {
+                let delay = retry_delay(policy, attempt);
+                warn!(
+                    "failed to fetch {} ({:#}), retrying in {}",
+                    watch_item.source,
+                    err,
+                    humantime::format_duration(delay)
+                );
+
+                sleep(delay).await;
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
@@ -0,0 +1,626 @@
+use anyhow::{bail, Context};
+use chrono::{
+    DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc, Weekday,
+};
+use chrono_tz::Tz;
+use ical::{parser::ical::component::IcalTimeZone, property::Property};
+
+/// Format of the DATE-TIME values (without the utc `Z` suffix)
+const DATE_TIME_FORMAT: &str = "%Y%m%dT%H%M%S";
+/// Format of the DATE values
+const DATE_FORMAT: &str = "%Y%m%d";
+
+/// A parsed DATE or DATE-TIME value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeValue {
+    /// A whole day (`VALUE=DATE`), this does not depend on any timezone.
+    Date(NaiveDate),
+    /// An instant, already converted to utc.
+    DateTime(DateTime<Utc>),
+}
+
+impl TimeValue {
+    /// Returns the instant of the value.
+    /// Dates are aligned on midnight utc so they keep the same day when formatted.
+    pub const fn to_utc(self) -> DateTime<Utc> {
+        match self {
+            Self::Date(date) => date.and_time(NaiveTime::MIN).and_utc(),
+            Self::DateTime(date) => date,
+        }
+    }
+
+    pub const fn is_date(self) -> bool {
+        matches!(self, Self::Date(_))
+    }
+}
+
+/// Returns the first value of a parameter of a property (e.g. `TZID`)
+pub fn param<'a>(property: &'a Property, name: &str) -> Option<&'a str> {
+    property
+        .params
+        .as_ref()?
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .and_then(|(_, values)| values.first())
+        .map(|value| value.trim_matches('"'))
+}
+
+/// Returns the value of the first property named `name` in a component
+fn property_value<'a>(properties: &'a [Property], name: &str) -> Option<&'a str> {
+    properties
+        .iter()
+        .find(|property| property.name == name)
+        .and_then(|property| property.value.as_deref())
+}
+
+/// Parses the local (wall clock) time of a DATE or DATE-TIME value.
+/// Dates are at midnight and the utc marker is ignored.
+pub fn parse_local(value: &str) -> Result<NaiveDateTime, anyhow::Error> {
+    if value.contains('T') {
+        Ok(NaiveDateTime::parse_from_str(
+            value.trim_end_matches('Z'),
+            DATE_TIME_FORMAT,
+        )?)
+    } else {
+        Ok(NaiveDate::parse_from_str(value, DATE_FORMAT)?.and_time(NaiveTime::MIN))
+    }
+}
+
+/// Parses a DURATION value (RFC 5545, section 3.3.6) such as `PT1H30M`, `P1D` or `-P1W`
+pub fn parse_duration(value: &str) -> Result<Duration, anyhow::Error> {
+    let (negative, rest) = match value.split_at_checked(1) {
+        Some(("-", rest)) => (true, rest),
+        Some(("+", rest)) => (false, rest),
+        _ => (false, value),
+    };
+    let rest = rest
+        .strip_prefix('P')
+        .with_context(|| format!("invalid duration: {value}"))?;
+
+    let mut duration = Duration::zero();
+    let mut number = String::new();
+    let mut time = false;
+
+    for c in rest.chars() {
+        match c {
+            '0'..='9' => number.push(c),
+            'T' if !time && number.is_empty() => time = true,
+            _ => {
+                let n: i64 = number
+                    .parse()
+                    .with_context(|| format!("invalid duration: {value}"))?;
+                number.clear();
+
+                let part = match (c, time) {
+                    ('W', false) => Duration::try_weeks(n),
+                    ('D', false) => Duration::try_days(n),
+                    ('H', true) => Duration::try_hours(n),
+                    ('M', true) => Duration::try_minutes(n),
+                    ('S', true) => Duration::try_seconds(n),
+                    _ => bail!("invalid duration: {value}"),
+                };
+                duration = part
+                    .and_then(|part| duration.checked_add(&part))
+                    .with_context(|| format!("duration out of range: {value}"))?;
+            }
+        }
+    }
+    if !number.is_empty() {
+        bail!("invalid duration: {value}");
+    }
+
+    Ok(if negative { -duration } else { duration })
+}
+
+/// Converts a local time to utc using a timezone.
+/// Ambiguous times (when the clocks go back) use the earliest instant and
+/// times skipped by a DST gap are shifted by one hour, like most calendar clients do.
+fn from_local(tz: Tz, local: NaiveDateTime) -> Result<DateTime<Utc>, anyhow::Error> {
+    let date = tz
+        .from_local_datetime(&local)
+        .earliest()
+        .or_else(|| {
+            tz.from_local_datetime(&(local + Duration::hours(1)))
+                .earliest()
+        })
+        .with_context(|| format!("{local} does not exist in {tz}"))?;
+
+    Ok(date.with_timezone(&Utc))
+}
+
+/// Parses an utc offset such as `+0200` or `-053000` into seconds
+fn parse_offset(value: &str) -> Result<i32, anyhow::Error> {
+    let (sign, digits) = match value.split_at_checked(1) {
+        Some(("+", digits)) => (1, digits),
+        Some(("-", digits)) => (-1, digits),
+        _ => bail!("invalid utc offset: {value}"),
+    };
+    if !matches!(digits.len(), 4 | 6) || !digits.bytes().all(|c| c.is_ascii_digit()) {
+        bail!("invalid utc offset: {value}");
+    }
+
+    let hours: i32 = digits[0..2].parse()?;
+    let minutes: i32 = digits[2..4].parse()?;
+    let seconds: i32 = digits.get(4..6).map_or(Ok(0), str::parse)?;
+
+    Ok(sign * (hours * 3600 + minutes * 60 + seconds))
+}
+
+/// Parses a two letters iCalendar weekday (`MO`, `TU`...)
+pub fn parse_weekday(value: &str) -> Result<Weekday, anyhow::Error> {
+    Ok(match value {
+        "MO" => Weekday::Mon,
+        "TU" => Weekday::Tue,
+        "WE" => Weekday::Wed,
+        "TH" => Weekday::Thu,
+        "FR" => Weekday::Fri,
+        "SA" => Weekday::Sat,
+        "SU" => Weekday::Sun,
+        _ => bail!("invalid weekday: {value}"),
+    })
+}
+
+/// Splits a `BYDAY` value (`-1SU`) into its position and its weekday.
+/// The split is done on characters, so a malformed value can't panic.
+pub fn split_weekday(value: &str) -> (&str, &str) {
+    let at = value.char_indices().rev().nth(1).map_or(0, |(at, _)| at);
+    value.split_at(at)
+}
+
+/// Returns the n-th weekday of a month, negative values count from the end of the month.
+/// (`-1` and `SU` is the last sunday of the month)
+pub fn nth_weekday(year: i32, month: u32, n: i8, weekday: Weekday) -> Option<NaiveDate> {
+    if n > 0 {
+        NaiveDate::from_weekday_of_month_opt(year, month, weekday, n.unsigned_abs())
+    } else {
+        let next_month = if month == 12 {
+            NaiveDate::from_ymd_opt(year + 1, 1, 1)?
+        } else {
+            NaiveDate::from_ymd_opt(year, month + 1, 1)?
+        };
+        let last = next_month.pred_opt()?;
+        let back = (7 + last.weekday().num_days_from_monday() - weekday.num_days_from_monday()) % 7;
+        let date = last
+            - Duration::days(i64::from(back))
+            - Duration::weeks(i64::from(n.unsigned_abs()) - 1);
+
+        (date.month() == month).then_some(date)
+    }
+}
+
+/// Computes the last onset before `local` of an observance (STANDARD or DAYLIGHT)
+/// of a `VTIMEZONE`. Only the yearly rules used by timezone definitions are supported.
+fn last_onset(
+    start: NaiveDateTime,
+    rule: Option<&str>,
+    local: NaiveDateTime,
+) -> Result<Option<NaiveDateTime>, anyhow::Error> {
+    let Some(rule) = rule else {
+        return Ok(Some(start).filter(|start| *start <= local));
+    };
+
+    let mut month = start.month();
+    let mut day = None;
+    let mut until = None;
+
+    for part in rule.split(';') {
+        match part.split_once('=') {
+            Some(("FREQ", "YEARLY")) | None => {}
+            Some(("FREQ", freq)) => bail!("unsupported timezone rule frequency: {freq}"),
+            Some(("BYMONTH", value)) => month = value.parse()?,
+            Some(("BYDAY", value)) => {
+                let (n, weekday) = split_weekday(value);
+                let n = if n.is_empty() {
+                    1
+                } else {
+                    n.trim_start_matches('+').parse()?
+                };
+                day = Some((n, parse_weekday(weekday)?));
+            }
+            Some(("UNTIL", value)) => until = Some(parse_local(value)?),
+            Some(_) => {}
+        }
+    }
+
+    for year in [local.year(), local.year() - 1] {
+        let date = match day {
+            Some((n, weekday)) => nth_weekday(year, month, n, weekday),
+            None => NaiveDate::from_ymd_opt(year, month, start.day()),
+        };
+        let Some(onset) = date.map(|date| date.and_time(start.time())) else {
+            continue;
+        };
+
+        if onset >= start && onset <= local && until.is_none_or(|until| onset <= until) {
+            return Ok(Some(onset));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Computes the utc offset (in seconds) of a local time using
+/// the observances of a `VTIMEZONE` block.
+fn embedded_offset(zone: &IcalTimeZone, local: NaiveDateTime) -> Result<i32, anyhow::Error> {
+    // latest onset before the local time and its offset
+    let mut current: Option<(NaiveDateTime, i32)> = None;
+    // first observance of the timezone, used for dates before any onset
+    let mut first: Option<(NaiveDateTime, i32)> = None;
+
+    for transition in &zone.transitions {
+        let properties = &transition.properties;
+        let start = NaiveDateTime::parse_from_str(
+            property_value(properties, "DTSTART").context("observance without DTSTART")?,
+            DATE_TIME_FORMAT,
+        )?;
+        let offset_to =
+            parse_offset(property_value(properties, "TZOFFSETTO").context("missing TZOFFSETTO")?)?;
+        let offset_from =
+            property_value(properties, "TZOFFSETFROM").map_or(Ok(offset_to), parse_offset)?;
+
+        if first.is_none_or(|(date, _)| start < date) {
+            first = Some((start, offset_from));
+        }
+
+        if let Some(onset) = last_onset(start, property_value(properties, "RRULE"), local)? {
+            if current.is_none_or(|(date, _)| onset > date) {
+                current = Some((onset, offset_to));
+            }
+        }
+    }
+
+    current
+        .or(first)
+        .map(|(_, offset)| offset)
+        .context("the timezone does not define any observance")
+}
+
+/// Resolves the DATE-TIME values of a calendar to utc dates.
+/// It holds the `VTIMEZONE` blocks of the calendar and the timezone
+/// used for floating times (the default timezone of the calendar).
+pub struct TimeContext<'a> {
+    default: Tz,
+    timezones: &'a [IcalTimeZone],
+}
+
+impl<'a> TimeContext<'a> {
+    pub const fn new(default: Tz, timezones: &'a [IcalTimeZone]) -> Self {
+        Self { default, timezones }
+    }
+
+    /// Parses a DATE or DATE-TIME property such as DTSTART or DTEND.
+    /// This supports the three forms of DATE-TIME of the iCalendar spec:
+    /// utc times (`20261016T080000Z`), times with a timezone reference (`TZID=Europe/Paris`)
+    /// and floating times, which use the default timezone of the calendar.
+    pub fn parse(&self, property: &Property) -> Result<TimeValue, anyhow::Error> {
+        let value = property
+            .value
+            .as_deref()
+            .context("missing property value")?;
+
+        self.convert(property, value, parse_local(value)?)
+    }
+
+    /// Parses a property holding a comma separated list of values (EXDATE, RDATE).
+    /// The form of each value is read from the value itself, since a list can mix them.
+    /// Periods (`start/end`) only keep their start.
+    pub fn parse_list(&self, property: &Property) -> Result<Vec<TimeValue>, anyhow::Error> {
+        let value = property
+            .value
+            .as_deref()
+            .context("missing property value")?;
+
+        value
+            .split(',')
+            .map(|item| {
+                let start = item.split_once('/').map_or(item, |(start, _)| start);
+                self.convert(property, start, parse_local(start)?)
+            })
+            .collect()
+    }
+
+    /// Interprets a local (wall clock) time in the same form as the value of a property:
+    /// a date, an utc time, a time in the timezone of the property or a floating time.
+    /// This is used to compute the recurrences of an event in its own timezone.
+    pub fn at(
+        &self,
+        property: &Property,
+        local: NaiveDateTime,
+    ) -> Result<TimeValue, anyhow::Error> {
+        let value = property
+            .value
+            .as_deref()
+            .context("missing property value")?;
+
+        self.convert(property, value, local)
+    }
+
+    /// Interprets a local time in the form of `value`, a single value of the property.
+    /// The `VALUE` parameter takes precedence over the shape of the value.
+    fn convert(
+        &self,
+        property: &Property,
+        value: &str,
+        local: NaiveDateTime,
+    ) -> Result<TimeValue, anyhow::Error> {
+        let is_date = match param(property, "VALUE") {
+            Some(kind) if kind.eq_ignore_ascii_case("DATE") => true,
+            Some(kind)
+                if kind.eq_ignore_ascii_case("DATE-TIME")
+                    || kind.eq_ignore_ascii_case("PERIOD") =>
+            {
+                false
+            }
+            _ => !value.contains('T'),
+        };
+
+        if is_date {
+            return Ok(TimeValue::Date(local.date()));
+        }
+
+        if value.ends_with('Z') {
+            return Ok(TimeValue::DateTime(local.and_utc()));
+        }
+
+        param(property, "TZID")
+            .map_or_else(
+                || from_local(self.default, local),
+                |tzid| self.resolve(tzid, local),
+            )
+            .map(TimeValue::DateTime)
+    }
+
+    /// Converts a local time using a timezone reference.
+    /// IANA names are resolved with `chrono-tz`, other names (e.g. Windows names used by Outlook)
+    /// are looked up in the `VTIMEZONE` blocks of the calendar.
+    fn resolve(&self, tzid: &str, local: NaiveDateTime) -> Result<DateTime<Utc>, anyhow::Error> {
+        if let Ok(tz) = tzid.parse::<Tz>() {
+            return from_local(tz, local);
+        }
+
+        let zone = self
+            .timezones
+            .iter()
+            .find(|zone| property_value(&zone.properties, "TZID") == Some(tzid))
+            .with_context(|| format!("unknown timezone: {tzid}"))?;
+
+        // some generators give the IANA name of the timezone in this property
+        if let Some(tz) = property_value(&zone.properties, "X-LIC-LOCATION")
+            .and_then(|location| location.parse::<Tz>().ok())
+        {
+            return from_local(tz, local);
+        }
+
+        let offset = embedded_offset(zone, local)?;
+        Ok((local - Duration::seconds(i64::from(offset))).and_utc())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, Weekday};
+    use chrono_tz::Tz;
+    use ical::parser::ical::component::IcalCalendar;
+
+    use super::{
+        from_local, last_onset, nth_weekday, parse_duration, split_weekday, TimeContext, TimeValue,
+    };
+
+    /// Timezone of Outlook, defined by its observances only
+    const ROMANCE: &str = "BEGIN:VTIMEZONE\nTZID:Romance Standard Time\n\
+        BEGIN:STANDARD\nDTSTART:16010101T030000\nTZOFFSETFROM:+0200\nTZOFFSETTO:+0100\n\
+        RRULE:FREQ=YEARLY;BYDAY=-1SU;BYMONTH=10\nEND:STANDARD\n\
+        BEGIN:DAYLIGHT\nDTSTART:16010101T020000\nTZOFFSETFROM:+0100\nTZOFFSETTO:+0200\n\
+        RRULE:FREQ=YEARLY;BYDAY=-1SU;BYMONTH=3\nEND:DAYLIGHT\nEND:VTIMEZONE\n";
+
+    /// Parses a calendar with the given blocks (timezones and events)
+    fn calendar(content: &str) -> IcalCalendar {
+        let source = format!("BEGIN:VCALENDAR\nVERSION:2.0\n{content}END:VCALENDAR\n");
+        ical::IcalParser::new(source.as_bytes())
+            .next()
+            .unwrap()
+            .unwrap()
+    }
+
+    /// Resolves the DTSTART of an event using the timezones of its calendar
+    fn resolve(timezones: &str, default: Tz, dtstart: &str) -> Result<TimeValue, anyhow::Error> {
+        let calendar = calendar(&format!(
+            "{timezones}BEGIN:VEVENT\nUID:1\n{dtstart}\nEND:VEVENT\n"
+        ));
+        let time = TimeContext::new(default, &calendar.timezones);
+
+        time.parse(&calendar.events[0].properties[1])
+    }
+
+    fn utc(value: &str) -> TimeValue {
+        TimeValue::DateTime(DateTime::parse_from_rfc3339(value).unwrap().to_utc())
+    }
+
+    fn local(value: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    #[test]
+    fn olson_timezone() {
+        assert_eq!(
+            resolve("", Tz::UTC, "DTSTART;TZID=Europe/Paris:20261016T080000").unwrap(),
+            utc("2026-10-16T06:00:00Z")
+        );
+        assert_eq!(
+            resolve("", Tz::UTC, "DTSTART;TZID=\"Europe/Paris\":20261216T080000").unwrap(),
+            utc("2026-12-16T07:00:00Z")
+        );
+        // the utc marker ignores the default timezone
+        assert_eq!(
+            resolve("", Tz::Europe__Paris, "DTSTART:20261016T080000Z").unwrap(),
+            utc("2026-10-16T08:00:00Z")
+        );
+        assert!(resolve("", Tz::UTC, "DTSTART;TZID=Nowhere:20261016T080000").is_err());
+    }
+
+    #[test]
+    fn floating_time() {
+        assert_eq!(
+            resolve("", Tz::America__New_York, "DTSTART:20261016T080000").unwrap(),
+            utc("2026-10-16T12:00:00Z")
+        );
+        assert_eq!(
+            resolve("", Tz::UTC, "DTSTART:20261016T080000").unwrap(),
+            utc("2026-10-16T08:00:00Z")
+        );
+    }
+
+    #[test]
+    fn date_value() {
+        let value = resolve("", Tz::Europe__Paris, "DTSTART;VALUE=DATE:20261101").unwrap();
+
+        assert!(value.is_date());
+        assert_eq!(
+            value,
+            TimeValue::Date(NaiveDate::from_ymd_opt(2026, 11, 1).unwrap())
+        );
+        // dates are aligned on midnight utc, whatever the timezone of the calendar
+        assert_eq!(value.to_utc(), utc("2026-11-01T00:00:00Z").to_utc());
+    }
+
+    #[test]
+    fn embedded_timezone() {
+        let dtstart = |value: &str| {
+            resolve(
+                ROMANCE,
+                Tz::UTC,
+                &format!("DTSTART;TZID=Romance Standard Time:{value}"),
+            )
+            .unwrap()
+        };
+
+        assert_eq!(dtstart("20260716T080000"), utc("2026-07-16T06:00:00Z"));
+        assert_eq!(dtstart("20261216T080000"), utc("2026-12-16T07:00:00Z"));
+        // around the switch to the standard time, on the last sunday of october
+        assert_eq!(dtstart("20261025T010000"), utc("2026-10-24T23:00:00Z"));
+        assert_eq!(dtstart("20261025T040000"), utc("2026-10-25T03:00:00Z"));
+    }
+
+    #[test]
+    fn timezone_location() {
+        let zone = "BEGIN:VTIMEZONE\nTZID:Paris\nX-LIC-LOCATION:Europe/Paris\nEND:VTIMEZONE\n";
+
+        assert_eq!(
+            resolve(zone, Tz::UTC, "DTSTART;TZID=Paris:20261016T080000").unwrap(),
+            utc("2026-10-16T06:00:00Z")
+        );
+    }
+
+    #[test]
+    fn onsets() {
+        let rule = Some("FREQ=YEARLY;BYDAY=-1SU;BYMONTH=10");
+        let start = local("1601-01-01 03:00");
+
+        assert_eq!(
+            last_onset(start, rule, local("2026-07-01 00:00")).unwrap(),
+            Some(local("2025-10-26 03:00"))
+        );
+        assert_eq!(
+            last_onset(start, rule, local("2026-10-25 03:00")).unwrap(),
+            Some(local("2026-10-25 03:00"))
+        );
+        // the rule ended before the local time
+        assert_eq!(
+            last_onset(
+                start,
+                Some("FREQ=YEARLY;BYDAY=-1SU;BYMONTH=10;UNTIL=20201025T010000Z"),
+                local("2026-07-01 00:00")
+            )
+            .unwrap(),
+            None
+        );
+        // a single onset, after the local time
+        assert_eq!(
+            last_onset(local("2030-01-01 00:00"), None, local("2026-07-01 00:00")).unwrap(),
+            None
+        );
+        assert!(last_onset(start, Some("FREQ=MONTHLY"), local("2026-07-01 00:00")).is_err());
+    }
+
+    #[test]
+    fn weekdays() {
+        let date = |year, month, day| NaiveDate::from_ymd_opt(year, month, day);
+
+        assert_eq!(nth_weekday(2026, 10, -1, Weekday::Sun), date(2026, 10, 25));
+        assert_eq!(nth_weekday(2026, 3, 2, Weekday::Sun), date(2026, 3, 8));
+        assert_eq!(nth_weekday(2026, 2, -1, Weekday::Mon), date(2026, 2, 23));
+        assert_eq!(nth_weekday(2026, 12, -2, Weekday::Thu), date(2026, 12, 24));
+        // february 2026 only has four mondays
+        assert_eq!(nth_weekday(2026, 2, 5, Weekday::Mon), None);
+        assert_eq!(nth_weekday(2026, 2, -5, Weekday::Mon), None);
+
+        assert_eq!(split_weekday("-1SU"), ("-1", "SU"));
+        assert_eq!(split_weekday("MO"), ("", "MO"));
+        assert_eq!(split_weekday("U"), ("", "U"));
+        assert_eq!(split_weekday("1éx"), ("1", "éx"));
+        assert!(last_onset(
+            local("2026-01-01 00:00"),
+            Some("BYDAY=éx"),
+            local("2026-07-01 00:00")
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn dst_transitions() {
+        // the clocks skip from 2:00 to 3:00, the time is shifted by the gap
+        assert_eq!(
+            from_local(Tz::Europe__Paris, local("2026-03-29 02:30")).unwrap(),
+            utc("2026-03-29T01:30:00Z").to_utc()
+        );
+        // the clocks go back from 3:00 to 2:00, the earliest instant is used
+        assert_eq!(
+            from_local(Tz::Europe__Paris, local("2026-10-25 02:30")).unwrap(),
+            utc("2026-10-25T00:30:00Z").to_utc()
+        );
+    }
+
+    #[test]
+    fn list_values() {
+        let calendar = calendar(
+            "BEGIN:VEVENT\nUID:1\n\
+             RDATE;VALUE=PERIOD:20261016T080000Z/PT1H,20261017T080000Z/20261017T090000Z\n\
+             RDATE:20261016T080000Z,20261017T080000\n\
+             EXDATE;VALUE=DATE:20261017,20261018\n\
+             END:VEVENT\n",
+        );
+        // the floating times use a timezone different from utc
+        let time = TimeContext::new(Tz::Europe__Paris, &calendar.timezones);
+        let properties = &calendar.events[0].properties;
+
+        // the utc periods aren't read as floating times
+        assert_eq!(
+            time.parse_list(&properties[1]).unwrap(),
+            vec![utc("2026-10-16T08:00:00Z"), utc("2026-10-17T08:00:00Z")]
+        );
+        // each value of the list has its own form
+        assert_eq!(
+            time.parse_list(&properties[2]).unwrap(),
+            vec![utc("2026-10-16T08:00:00Z"), utc("2026-10-17T06:00:00Z")]
+        );
+        assert_eq!(
+            time.parse_list(&properties[3]).unwrap(),
+            vec![
+                TimeValue::Date(NaiveDate::from_ymd_opt(2026, 10, 17).unwrap()),
+                TimeValue::Date(NaiveDate::from_ymd_opt(2026, 10, 18).unwrap())
+            ]
+        );
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("PT1H30M").unwrap(), Duration::minutes(90));
+        assert_eq!(parse_duration("P1DT2H").unwrap(), Duration::hours(26));
+        assert_eq!(parse_duration("-P1W").unwrap(), -Duration::weeks(1));
+        assert_eq!(parse_duration("+PT15S").unwrap(), Duration::seconds(15));
+
+        for invalid in ["1H", "P1H", "PT1D", "P1", "P9999999999999999W"] {
+            assert!(parse_duration(invalid).is_err(), "{invalid}");
+        }
+    }
+}
@@ -1,7 +1,12 @@
+use anyhow::Context;
+use chrono_tz::Tz;
 use config::{File, Environment};
 use poise::serenity_prelude::{ChannelId, RoleId};
-use serde::Deserialize;
-use std::collections::HashMap;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use std::{collections::HashMap, fmt, fs, time::Duration};
+
+use crate::calendar::{Field, UpdateKind};
 
 #[derive(Deserialize, Debug, Clone, Default)]
 /// Configuration regarding the discord bot configuration
@@ -10,14 +15,375 @@ pub struct DiscordConfig {
     pub token: String,
 }
 
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+/// The text fields of an event
+pub enum TextField {
+    Summary,
+    Description,
+    Location,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+/// A find/replace rule applied to the text of the events when they are fetched.
+/// This is used to remove the noise added by some sources.
+pub struct CleanupRule {
+    /// Regular expression to search for, compiled when the configuration is loaded
+    #[serde(deserialize_with = "deserialize_regex")]
+    pub pattern: Regex,
+    /// Replacement of the matched text, this can use the groups of the regex (`$1`)
+    #[serde(default)]
+    pub replace: String,
+    /// Fields the rule is applied to, all of them by default
+    #[serde(default = "all_text_fields")]
+    pub fields: Vec<TextField>,
+}
+
+fn deserialize_regex<'de, D>(deserializer: D) -> Result<Regex, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let pattern = String::deserialize(deserializer)?;
+    Regex::new(&pattern).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let duration = String::deserialize(deserializer)?;
+    humantime::parse_duration(&duration).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_optional_timezone<'de, D>(deserializer: D) -> Result<Option<Tz>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|timezone| {
+            timezone
+                .parse::<Tz>()
+                .map_err(|_| format!("invalid timezone: {timezone}"))
+        })
+        .transpose()
+        .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_optional_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|duration| humantime::parse_duration(&duration))
+        .transpose()
+        .map_err(serde::de::Error::custom)
+}
+
+fn all_update_kinds() -> Vec<UpdateKind> {
+    vec![
+        UpdateKind::Created,
+        UpdateKind::Updated,
+        UpdateKind::Removed,
+        UpdateKind::Cancelled,
+    ]
+}
+
+fn all_fields() -> Vec<Field> {
+    vec![
+        Field::Summary,
+        Field::Start,
+        Field::End,
+        Field::Location,
+        Field::Description,
+        Field::Status,
+    ]
+}
+
+fn all_text_fields() -> Vec<TextField> {
+    vec![
+        TextField::Summary,
+        TextField::Description,
+        TextField::Location,
+    ]
+}
+
+/// Cleanup rules used when a calendar does not specify any.
+/// This removes the "(Exporté le ...)" suffix added by ADE to the descriptions,
+/// the other parentheses are kept.
+fn default_cleanup() -> Vec<CleanupRule> {
+    vec![CleanupRule {
+        pattern: Regex::new(r"\s*\(Exporté le[^)]*\)").expect("invalid default cleanup regex"),
+        replace: String::new(),
+        fields: vec![TextField::Description],
+    }]
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+/// A secret value of the configuration, such as a password or a token.
+/// It can be written in the configuration or loaded from an environment variable
+/// (`{ env = "NAME" }`) or a file (`{ file = "/run/secrets/name" }`).
+/// The value is read when it's used and never shown in the logs.
+pub enum Secret {
+    Plain(String),
+    Env { env: String },
+    File { file: String },
+}
+
+impl Secret {
+    /// Reads the value of the secret.
+    /// The errors only mention where the secret is stored, never its value.
+    pub fn expose(&self) -> Result<String, anyhow::Error> {
+        match self {
+            Self::Plain(value) => Ok(value.clone()),
+            Self::Env { env } => std::env::var(env)
+                .with_context(|| format!("failed to read the environment variable {env}")),
+            Self::File { file } => {
+                let path = shellexpand::full_with_context_no_errors(
+                    file,
+                    || dirs::home_dir().and_then(|p| p.to_str().map(ToString::to_string)),
+                    |f| std::env::var(f).ok(),
+                );
+                let value = fs::read_to_string(path.as_ref())
+                    .with_context(|| format!("failed to read the secret file {file}"))?;
+
+                // files usually end with a new line
+                Ok(value.trim_end_matches(['\r', '\n']).to_string())
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Plain(_) => write!(f, "Secret(<redacted>)"),
+            Self::Env { env } => write!(f, "Secret(env: {env})"),
+            Self::File { file } => write!(f, "Secret(file: {file})"),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+/// Authentication used to fetch a private source
+pub enum Auth {
+    /// HTTP basic authentication
+    Basic { username: String, password: Secret },
+    /// Token sent in the `Authorization: Bearer` header
+    Bearer { token: Secret },
+}
+
+#[derive(Deserialize, Debug, Clone)]
+/// Settings of an ADE source (`ade://` or `ades://`).
+/// The bot builds the export request from these ids and the range covered by the calendar.
+pub struct AdeConfig {
+    /// Id of the ADE project, this usually changes every school year
+    pub project: u32,
+    /// Ids of the exported resources (groups, rooms, teachers...)
+    pub resources: Vec<u32>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+/// Timeouts and retries used when fetching a source.
+/// Transient errors (timeouts, connection errors, `429` and `5xx` statuses) are retried
+/// with an exponential backoff, the other errors fail the fetch immediately.
+pub struct FetchPolicy {
+    /// Maximum time to connect to the server
+    #[serde(
+        default = "default_connect_timeout",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub connect_timeout: Duration,
+    /// Maximum time to wait for data from the server
+    #[serde(
+        default = "default_read_timeout",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub read_timeout: Duration,
+    /// Maximum duration of a fetch attempt, including all its requests
+    #[serde(default = "default_timeout", deserialize_with = "deserialize_duration")]
+    pub timeout: Duration,
+    /// Number of retries after a transient error
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    /// Delay before the first retry, doubled on every retry and randomized
+    /// so the calendars of a same server don't retry at the same time
+    #[serde(default = "default_backoff", deserialize_with = "deserialize_duration")]
+    pub backoff: Duration,
+    /// Maximum delay between two retries
+    #[serde(
+        default = "default_max_backoff",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub max_backoff: Duration,
+}
+
+const fn default_connect_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+const fn default_read_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+const fn default_timeout() -> Duration {
+    Duration::from_mins(2)
+}
+
+const fn default_retries() -> u32 {
+    3
+}
+
+const fn default_backoff() -> Duration {
+    Duration::from_secs(2)
+}
+
+const fn default_max_backoff() -> Duration {
+    Duration::from_mins(1)
+}
+
+impl Default for FetchPolicy {
+    fn default() -> Self {
+        Self {
+            connect_timeout: default_connect_timeout(),
+            read_timeout: default_read_timeout(),
+            timeout: default_timeout(),
+            retries: default_retries(),
+            backoff: default_backoff(),
+            max_backoff: default_max_backoff(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+/// Protection against the sources returning an empty or truncated calendar.
+/// When a fetch removes too many events, the update is quarantined instead of applied
+/// and the admins are notified. It's applied once the next fetches show the same removals,
+/// or when an admin confirms it.
+pub struct Safeguard {
+    /// Fetches removing less events than this are always applied
+    #[serde(default = "default_min_removals")]
+    pub min_removals: usize,
+    /// Maximum number of events removed by a single fetch
+    #[serde(default = "default_max_removals")]
+    pub max_removals: usize,
+    /// Maximum share of the events of the checked range removed by a single fetch (0 to 1)
+    #[serde(default = "default_max_ratio")]
+    pub max_ratio: f64,
+    /// Number of consecutive fetches after the first one that must show the removals
+    /// before they're applied
+    #[serde(default = "default_confirmations")]
+    pub confirmations: u32,
+}
+
+const fn default_min_removals() -> usize {
+    3
+}
+
+const fn default_max_removals() -> usize {
+    10
+}
+
+const fn default_max_ratio() -> f64 {
+    0.5
+}
+
+const fn default_confirmations() -> u32 {
+    1
+}
+
+impl Default for Safeguard {
+    fn default() -> Self {
+        Self {
+            min_removals: default_min_removals(),
+            max_removals: default_max_removals(),
+            max_ratio: default_max_ratio(),
+            confirmations: default_confirmations(),
+        }
+    }
+}
+
+impl Safeguard {
+    /// Checks if the removals of a fetch must be quarantined,
+    /// `total` is the number of events of the checked range before the fetch.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn is_exceeded(&self, removals: usize, total: usize) -> bool {
+        removals >= self.min_removals
+            && (removals > self.max_removals || removals as f64 > self.max_ratio * total as f64)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+/// Selects the created events of a calendar that are announced
+pub struct Creations {
+    /// Announce the events of the first import of the calendar, it's silent by default
+    #[serde(default)]
+    pub announce_bootstrap: bool,
+    /// Only announce the events starting before the last event known before the fetch
+    /// (in the range checked by the calendar), for the sources publishing further ahead
+    /// from time to time. The new occurrences of the recurring events are never announced.
+    #[serde(default)]
+    pub known_range_only: bool,
+    /// Only announce the events starting within this time of the fetch
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub horizon: Option<Duration>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+/// Selects the updates of a calendar that are announced.
+/// Everything is announced by default.
+pub struct Notify {
+    /// Kinds of updates announced
+    #[serde(default = "all_update_kinds")]
+    pub kinds: Vec<UpdateKind>,
+    /// Fields whose change is announced, an updated event is ignored if none of them changed
+    #[serde(default = "all_fields")]
+    pub fields: Vec<Field>,
+    /// Only announce the changes of the events starting within this time
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub lookahead: Option<Duration>,
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self {
+            kinds: all_update_kinds(),
+            fields: all_fields(),
+            lookahead: None,
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, Clone, Default)]
+/// Settle period of the changes of a calendar.
+/// A change is only announced once it persisted for `cycles` more fetches and for `time`,
+/// the changes reverted in the meantime are dropped. Both are disabled by default.
+pub struct Settle {
+    /// Number of fetches after the first one that must show the change
+    #[serde(default)]
+    pub cycles: u32,
+    /// Minimum time since the change was seen
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub time: Duration,
+}
+
+impl Settle {
+    pub const fn is_enabled(&self) -> bool {
+        self.cycles > 0 || !self.time.is_zero()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
 /// A calendar item is simply a calendar watched by the bot
 /// this includes links such as the source (url) the discord channel,
 /// roles and `fetch_time`.
 /// Check each field for the documentation and usages.
 pub struct CalendarItem {
     /// The source url of the calendar.
-    /// this can use the http or https protocol.
+    /// this can use the http, https, webcal or webcals protocol, or be a local
+    /// `file://` path to an ics file or a directory of ics files.
+    /// Calendar collections (RFC 4791) use the caldav or caldavs protocol and ADE servers ade or ades.
     pub source: String,
     /// A list of discord channels where alerts are going to be sent
     pub channel: Vec<ChannelId>,
@@ -30,6 +396,89 @@ pub struct CalendarItem {
     /// You should always try to put it above what's outputed to avoid missing any deletion
     /// events.
     pub time_amount: String,
+    /// Timezone used for the floating times of the calendar (times without a `TZID` or utc marker).
+    /// This must be an IANA timezone name such as `Europe/Paris`, checked when the configuration
+    /// is loaded. Defaults to utc.
+    #[serde(default, deserialize_with = "deserialize_optional_timezone")]
+    pub timezone: Option<Tz>,
+    /// Treat the transparent events (`TRANSP:TRANSPARENT`) as cancelled events.
+    /// This is meant for sources that flag cancelled classes this way instead of using `STATUS`.
+    #[serde(default)]
+    pub cancel_transparent: bool,
+    /// Ordered list of find/replace rules applied to the summary, description and location
+    /// of the events. Defaults to the removal of the export date added by ADE.
+    #[serde(default = "default_cleanup")]
+    pub cleanup: Vec<CleanupRule>,
+    /// Authentication used to fetch the source.
+    /// ADE sources use the basic credentials to open a session on the ADE api.
+    pub auth: Option<Auth>,
+    /// Additional headers sent with the requests to the source (e.g. an api key)
+    #[serde(default)]
+    pub headers: HashMap<String, Secret>,
+    /// Settings of an ADE source
+    pub ade: Option<AdeConfig>,
+    /// Timeouts and retries of the calendar, replaces the policy of the `[calendar]` block
+    pub fetch: Option<FetchPolicy>,
+    /// Thresholds of the mass-deletion safeguard
+    #[serde(default)]
+    pub safeguard: Safeguard,
+    /// Settle period of the changes, used to ignore the sources flapping between two states
+    #[serde(default)]
+    pub settle: Settle,
+    /// Created events announced in the channels of the calendar
+    #[serde(default)]
+    pub creations: Creations,
+    /// Updates announced in the channels of the calendar
+    #[serde(default)]
+    pub notify: Notify,
+    /// Minimum similarity (0 to 1) for a removed and a created event to be announced as
+    /// a single updated event, for the sources changing the uid of the edited events.
+    /// The summary, start, duration and location are compared. Defaults to 0.8, set above 1 to disable.
+    pub rematch: Option<f64>,
+}
+
+impl Default for CalendarItem {
+    fn default() -> Self {
+        Self {
+            source: String::new(),
+            channel: Vec::new(),
+            role: Vec::new(),
+            time_amount: String::new(),
+            timezone: None,
+            cancel_transparent: false,
+            cleanup: default_cleanup(),
+            auth: None,
+            headers: HashMap::new(),
+            ade: None,
+            fetch: None,
+            safeguard: Safeguard::default(),
+            settle: Settle::default(),
+            creations: Creations::default(),
+            notify: Notify::default(),
+            rematch: None,
+        }
+    }
+}
+
+impl CalendarItem {
+    /// Returns the fetch policy of the calendar, or the default one of the configuration
+    pub fn fetch_policy<'a>(&'a self, config: &'a CalendarConfig) -> &'a FetchPolicy {
+        self.fetch.as_ref().unwrap_or(&config.fetch)
+    }
+
+    /// Returns the similarity threshold used to pair the removed and created events
+    pub fn rematch_threshold(&self) -> f64 {
+        self.rematch.unwrap_or(0.8)
+    }
+
+    /// Parses the `time_amount` of the calendar
+    pub fn time_range(&self) -> Result<chrono::Duration, anyhow::Error> {
+        chrono::Duration::from_std(
+            humantime::parse_duration(&self.time_amount)
+                .context("invalid format in the time_amount duration")?,
+        )
+        .context("failed to get a duration from standard")
+    }
 }
 
 #[derive(Deserialize, Debug, Clone, Default)]
@@ -44,15 +493,83 @@ pub struct CalendarConfig {
     /// Specifies the time between updates for all the calendars.
     /// This uses the cron syntax.
     pub refetch: String,
+    /// Timeouts and retries used for the calendars without their own policy
+    #[serde(default)]
+    pub fetch: FetchPolicy,
+    /// Maximum number of sources fetched at the same time
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+    /// Discord channel where the outages of the sources are announced
+    pub alert_channel: Option<ChannelId>,
+    /// Number of consecutive failed fetches before a source is announced as down
+    #[serde(default = "default_alert_after")]
+    pub alert_after: u32,
 }
 
-#[derive(Deserialize, Debug, Clone, Default)]
+const fn default_alert_after() -> u32 {
+    3
+}
+
+const fn default_concurrency() -> usize {
+    4
+}
+
+#[derive(Deserialize, Debug, Clone)]
+/// Bounds of the history of the changes kept in the database
+pub struct Retention {
+    /// The changes older than this are dropped
+    #[serde(default = "default_max_age", deserialize_with = "deserialize_duration")]
+    pub max_age: Duration,
+    /// Maximum number of changes kept, the oldest ones are dropped first
+    #[serde(default = "default_max_changes")]
+    pub max_changes: usize,
+}
+
+const fn default_max_age() -> Duration {
+    Duration::from_hours(24 * 180)
+}
+
+const fn default_max_changes() -> usize {
+    10_000
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            max_age: default_max_age(),
+            max_changes: default_max_changes(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
 /// Specifies the configuration for the database.
 ///! The database is very much experimental and should be used with caution.
 pub struct StorageConfig {
     /// Relative or absolute path to the database file.
     /// this file is versionned and need to be saved on a real disk.
     pub path: String,
+    /// Retention of the history of the changes
+    #[serde(default)]
+    pub history: Retention,
+    /// Number of previous versions of the database kept next to it (`<path>.1` is the latest).
+    /// They're used when the database can't be read at startup.
+    #[serde(default = "default_snapshots")]
+    pub snapshots: usize,
+}
+
+const fn default_snapshots() -> usize {
+    3
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            path: String::new(),
+            history: Retention::default(),
+            snapshots: default_snapshots(),
+        }
+    }
 }
 
 #[derive(Deserialize, Debug, Clone, Default)]
@@ -0,0 +1,33 @@
+use chrono::Utc;
+use poise::CreateReply;
+
+use crate::{
+    bot::CommandContext,
+    calendar::{process_events, significant_updates},
+};
+
+use super::summary::autocomplete_schedule;
+
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_GUILD")]
+/// Applique la mise à jour en quarantaine d'un emploi du temps
+pub async fn confirm(
+    ctx: CommandContext<'_>,
+
+    #[description = "L'emploi du temps à mettre à jour"]
+    #[autocomplete = "autocomplete_schedule"]
+    schedule: String,
+) -> Result<(), anyhow::Error> {
+    let data = ctx.data();
+    let updates = data.calendar_manager.write().await.confirm(&schedule)?;
+    let updates = significant_updates(&data.config, updates, Utc::now());
+
+    let reply = CreateReply::default().ephemeral(true).content(format!(
+        "La mise à jour de {schedule} a été appliquée: {} changements annoncés.",
+        updates.values().map(Vec::len).sum::<usize>()
+    ));
+    ctx.send(reply).await?;
+
+    process_events(&data.config, updates, ctx.serenity_context().http.clone()).await;
+
+    Ok(())
+}
@@ -0,0 +1,123 @@
+use chrono::{Duration, Utc};
+use futures::Stream;
+use poise::{
+    serenity_prelude::{Color, CreateEmbed},
+    CreateReply,
+};
+use std::fmt::Write;
+
+use crate::{bot::CommandContext, calendar::history::Change};
+
+use super::summary::autocomplete_schedule;
+
+/// Maximum number of changes shown, the most recent ones are kept
+const MAX_CHANGES: usize = 15;
+/// Maximum number of suggestions of the autocompletion (limit of discord)
+const MAX_SUGGESTIONS: usize = 25;
+
+/// Suggests the summaries of the events of the history
+async fn autocomplete_course<'a>(
+    ctx: CommandContext<'_>,
+    partial: &'a str,
+) -> impl Stream<Item = String> + 'a {
+    let partial = partial.to_lowercase();
+    let summaries: Vec<String> = ctx
+        .data()
+        .calendar_manager
+        .read()
+        .await
+        .store
+        .history
+        .summaries()
+        .into_iter()
+        .filter(|summary| summary.to_lowercase().contains(&partial))
+        .take(MAX_SUGGESTIONS)
+        .map(ToString::to_string)
+        .collect();
+
+    futures::stream::iter(summaries)
+}
+
+/// Formats a change for the embed
+fn describe(change: &Change) -> Result<String, std::fmt::Error> {
+    let event = change.event();
+    let mut line = format!(
+        "<t:{}:f> **{}** {}\n> {}",
+        change.date.timestamp(),
+        change.kind.label(),
+        event.summary,
+        event.time_span()
+    );
+
+    if !event.location.is_empty() {
+        write!(line, " - {}", event.location)?;
+    }
+    if !change.changes.is_empty() {
+        let fields: Vec<&str> = change.changes.iter().map(|field| field.label()).collect();
+        write!(line, "\n> Modifié: {}", fields.join(", "))?;
+    }
+
+    Ok(line)
+}
+
+#[poise::command(slash_command, guild_only)]
+/// Affiche l'historique des changements d'un cours
+pub async fn history(
+    ctx: CommandContext<'_>,
+
+    #[description = "L'emploi du temps à inspecter"]
+    #[autocomplete = "autocomplete_schedule"]
+    schedule: String,
+
+    #[description = "Le nom ou l'uid du cours"]
+    #[autocomplete = "autocomplete_course"]
+    course: String,
+
+    #[description = "Nombre de jours à afficher (30 par défaut)"]
+    #[min = 1]
+    days: Option<u32>,
+) -> Result<(), anyhow::Error> {
+    let to = Utc::now();
+    let from = to - Duration::days(days.unwrap_or(30).into());
+
+    let manager = ctx.data().calendar_manager.read().await;
+    let history = &manager.store.history;
+
+    // the course is looked up by uid, then by name
+    let mut changes: Vec<&Change> = history.event(&schedule, &course, from, to).collect();
+    if changes.is_empty() {
+        changes = history
+            .query(&schedule, from, to)
+            .filter(|change| change.mentions(&course))
+            .collect();
+    }
+
+    let mut description = changes
+        .iter()
+        .rev()
+        .take(MAX_CHANGES)
+        .rev()
+        .map(|change| describe(change))
+        .collect::<Result<Vec<_>, _>>()?
+        .join("\n\n");
+    if changes.is_empty() {
+        description = "Aucun changement sur cette période.".to_string();
+    } else if changes.len() > MAX_CHANGES {
+        write!(
+            description,
+            "\n\n... et {} changements plus anciens",
+            changes.len() - MAX_CHANGES
+        )?;
+    }
+
+    let embed = CreateEmbed::default()
+        .title(format!("Historique de {course} ({schedule})"))
+        .color(Color::BLUE)
+        .description(description);
+    drop(manager);
+
+    ctx.send(CreateReply::default().ephemeral(true).embed(embed))
+        .await?;
+
+    Ok(())
+}
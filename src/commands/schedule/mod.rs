@@ -1 +1,5 @@
+pub mod confirm;
+pub mod history;
+pub mod report;
+pub mod status;
 pub mod summary;
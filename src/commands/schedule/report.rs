@@ -0,0 +1,76 @@
+use anyhow::Context;
+use poise::{
+    serenity_prelude::{Color, CreateEmbed},
+    CreateReply,
+};
+use std::fmt::Write;
+
+use crate::bot::CommandContext;
+
+use super::summary::autocomplete_schedule;
+
+/// Maximum length of a raw value shown in the report
+const VALUE_PREVIEW: usize = 80;
+/// Maximum number of issues shown, to stay below the size limit of an embed
+const MAX_ISSUES: usize = 10;
+
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_GUILD")]
+/// Affiche les erreurs de lecture d'un emploi du temps
+pub async fn report(
+    ctx: CommandContext<'_>,
+
+    #[description = "L'emploi du temps à inspecter"]
+    #[autocomplete = "autocomplete_schedule"]
+    schedule: String,
+) -> Result<(), anyhow::Error> {
+    let report = ctx
+        .data()
+        .calendar_manager
+        .read()
+        .await
+        .reports
+        .get(&schedule)
+        .cloned()
+        .context("Cet emploi du temps n'a pas encore été récupéré.")?;
+
+    let mut description = format!(
+        "Dernière lecture <t:{}>: {} évènements, {} erreurs.\n",
+        report.date.timestamp(),
+        report.events,
+        report.issues.len()
+    );
+
+    for issue in report.issues.iter().take(MAX_ISSUES) {
+        let value: String = issue.value.chars().take(VALUE_PREVIEW).collect();
+        write!(
+            description,
+            "\n**#{}** `{}` - `{}`: `{}`\n> {}",
+            issue.index,
+            issue.uid.as_deref().unwrap_or("uid inconnu"),
+            issue.property,
+            value,
+            issue.error
+        )?;
+    }
+    if report.issues.len() > MAX_ISSUES {
+        write!(
+            description,
+            "\n\n... et {} autres",
+            report.issues.len() - MAX_ISSUES
+        )?;
+    }
+
+    let embed = CreateEmbed::default()
+        .title(format!("Rapport de lecture de {schedule}"))
+        .color(if report.issues.is_empty() {
+            Color::DARK_GREEN
+        } else {
+            Color::ORANGE
+        })
+        .description(description);
+
+    ctx.send(CreateReply::default().ephemeral(true).embed(embed))
+        .await?;
+
+    Ok(())
+}
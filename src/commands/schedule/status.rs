@@ -0,0 +1,64 @@
+use poise::{
+    serenity_prelude::{Color, CreateEmbed},
+    CreateReply,
+};
+use std::fmt::Write;
+
+use crate::{bot::CommandContext, calendar::health::Health};
+
+/// Maximum length of the error shown for a source
+const ERROR_PREVIEW: usize = 200;
+
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_GUILD")]
+/// Affiche l'état des sources des emplois du temps
+pub async fn status(ctx: CommandContext<'_>) -> Result<(), anyhow::Error> {
+    let data = ctx.data();
+    let health = data.calendar_manager.read().await.health.clone();
+
+    let mut names: Vec<&String> = data.config.calendar.calendars.keys().collect();
+    names.sort();
+
+    let mut description = String::new();
+    for name in names {
+        let Some(health) = health.get(name) else {
+            write!(description, "\n⏳ **{name}** - pas encore récupéré")?;
+            continue;
+        };
+
+        let last_success = health.last_success.map_or_else(
+            || "jamais".to_string(),
+            |date| format!("<t:{}:R>", date.timestamp()),
+        );
+
+        if health.is_healthy() {
+            write!(description, "\n✅ **{name}** - récupéré {last_success}")?;
+        } else {
+            let error: String = health
+                .last_error
+                .as_deref()
+                .unwrap_or_default()
+                .chars()
+                .take(ERROR_PREVIEW)
+                .collect();
+            write!(
+                description,
+                "\n❌ **{name}** - {} échecs, dernier succès: {last_success}\n> {error}",
+                health.consecutive_failures
+            )?;
+        }
+    }
+
+    let embed = CreateEmbed::default()
+        .title("État des sources")
+        .color(if health.values().all(Health::is_healthy) {
+            Color::DARK_GREEN
+        } else {
+            Color::RED
+        })
+        .description(description);
+
+    ctx.send(CreateReply::default().ephemeral(true).embed(embed))
+        .await?;
+
+    Ok(())
+}
@@ -1,5 +1,5 @@
 use anyhow::Context;
-use chrono::{Duration, Utc};
+use chrono::{Duration, NaiveTime, Utc};
 use futures::{Stream, StreamExt};
 use log::info;
 use poise::{serenity_prelude::CreateEmbed, CreateReply};
@@ -13,7 +13,14 @@ use crate::bot::CommandContext;
     rename = "schedule",
     name_localized("en-US", "schedule"),
     description_localized("en-US", "Command used to manage the schedules"),
-    subcommands("summary", "groups")
+    subcommands(
+        "summary",
+        "groups",
+        "super::report::report",
+        "super::status::status",
+        "super::confirm::confirm",
+        "super::history::history"
+    )
 )]
 pub async fn root(_: CommandContext<'_>) -> Result<(), anyhow::Error> {
     unreachable!();
@@ -47,7 +54,7 @@ pub async fn groups(ctx: CommandContext<'_>) -> Result<(), anyhow::Error> {
 }
 
 #[allow(clippy::unused_async)]
-async fn autocomplete_schedule<'a>(
+pub async fn autocomplete_schedule<'a>(
     ctx: CommandContext<'_>,
     partial: &'a str,
 ) -> impl Stream<Item = String> + 'a {
@@ -83,6 +90,8 @@ pub async fn summary(
     #[description = "L'emploi du temps à inspecter"]
     #[autocomplete = "autocomplete_schedule"]
     schedule: Option<String>,
+
+    #[description = "Afficher les cours annulés"] cancelled: Option<bool>,
 ) -> Result<(), anyhow::Error> {
     let data = ctx.data();
     let member = &ctx.author_member().await;
@@ -107,10 +116,15 @@ pub async fn summary(
 
     let reader = data.calendar_manager.read().await;
 
+    // all-day events are aligned on midnight, so we start the search
+    // at the beginning of the day to include the events of today.
+    // Multi-day events which started before today are included as long as they are running.
+    let day_start = from.date_naive().and_time(NaiveTime::MIN).and_utc();
+
     let mut events = calendars
         .map(|(name, _)| {
             let calendar = reader.store.data.get(name)?;
-            let events = calendar.get_range(from, duration);
+            let events = calendar.get_overlapping(day_start, to);
 
             info!("found {} events for {}", events.len(), name);
 
@@ -126,23 +140,37 @@ pub async fn summary(
         })
         .context("Could't find any calendar matching.")?;
 
+    // cancelled events are hidden unless asked otherwise
+    if !cancelled.unwrap_or(false) {
+        events.retain(|event| !event.is_cancelled());
+    }
+    events.sort_by_key(|event| event.start);
+
+    // all-day events are shown as banners on top of the summary
+    let (all_day, timed): (Vec<_>, Vec<_>) = events.iter().partition(|event| event.all_day);
+
+    let mut description = format!(
+        "Voici les cours du <t:{}> au <t:{}>:",
+        from.timestamp(),
+        to.timestamp()
+    );
+    for event in &all_day {
+        write!(description, "\n📅 **{}** - {}", event.summary, event.time_span())?;
+    }
+
     let mut reply = CreateReply::default().ephemeral(true);
     let mut embed = CreateEmbed::default()
         .title("Résumé des événements à venir")
         .color(0x3498DB)
-        .description(format!(
-            "Voici les cours du <t:{}> au <t:{}>:",
-            from.timestamp(),
-            to.timestamp()
-        ));
+        .description(description);
 
-    for event in &mut events[0..5] {
+    for event in timed.iter().filter(|event| event.start >= from).take(5) {
         let mut string = format!(
-            "<t:{}> à <t:{}> - **{}**\n```{}```\n\n",
-            event.start.timestamp(),
-            event.end.timestamp(),
+            "{} - **{}**{}\n{}\n\n",
+            event.time_span(),
             event.summary,
-            event.description.replace("\\n", " ").trim()
+            if event.is_cancelled() { " (annulé)" } else { "" },
+            event.description_block()
         );
         if !event.location.is_empty() {
             string += format!("`{}`", &event.location).as_str();